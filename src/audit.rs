@@ -0,0 +1,54 @@
+use crate::{slots, snapshot_label, Remote, Spec};
+use chrono::{DateTime, Utc};
+
+/// Checks `remote`'s snapshots against `spec` and prints every slot that has
+/// ended without a snapshot in it. Returns the number of such gaps.
+pub fn audit(remote: &Remote, spec: &Spec, now: &DateTime<Utc>) -> usize {
+    let snapshots = remote.list_snapshots();
+    let slots = slots(now, spec);
+
+    let mut gaps = 0;
+    // (kind, filled, audited) in the order of the keep specification
+    let mut summary: Vec<(&str, usize, usize)> = Vec::new();
+    for slot in &slots {
+        // the current period may still get its snapshot
+        if slot.end > *now {
+            continue;
+        }
+        // labeled snapshots are taken on demand, not on schedule
+        let filled = snapshots
+            .iter()
+            .filter(|x| snapshot_label(&x.path).is_none())
+            .any(|x| x.time > slot.start && x.time <= slot.end);
+        if !filled {
+            println!(
+                "Missing {} snapshot for {}.",
+                slot.kind,
+                slot.start.format("%Y-%m-%d %H:%M")
+            );
+            gaps += 1;
+        }
+        match summary.iter_mut().find(|x| x.0 == slot.kind) {
+            Some(entry) => {
+                entry.1 += filled as usize;
+                entry.2 += 1;
+            }
+            None => summary.push((slot.kind, filled as usize, 1)),
+        }
+    }
+
+    if summary.is_empty() {
+        println!("{}: no slots have ended yet.", remote.dataset);
+        return 0;
+    }
+    println!(
+        "{}: {} slots filled.",
+        remote.dataset,
+        summary
+            .iter()
+            .map(|(kind, filled, audited)| format!("{} of {} {}", filled, audited, kind))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    gaps
+}
@@ -0,0 +1,122 @@
+#[cfg(feature = "replay")]
+use crate::replay;
+use crate::{collect_stderr, throttle, trace, CommandError, Remote};
+use std::{
+    io::{self, Write},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+/// Fills `buf` with xorshift noise, which no compression along the way can
+/// shrink.
+fn fill_random(buf: &mut [u8], state: &mut u64) {
+    for chunk in buf.chunks_mut(8) {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// User and system CPU time used by `who`, e.g. `libc::RUSAGE_SELF`.
+fn cpu_time(who: libc::c_int) -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(who, &mut usage) };
+    let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Streams `size` bytes of generated data to `dd of=/dev/null` on `remote`,
+/// through the same transport and rate limit a send would use, and reports
+/// the throughput and CPU time spent. zfs is not involved.
+pub fn bench(remote: &Remote, size: u64, zeros: bool, bwlimit: Option<&throttle::Schedule>) {
+    let (mut stream, mut generated) = io::pipe().unwrap();
+    let generator = std::thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            if !zeros {
+                fill_random(&mut buf[..n], &mut state);
+            }
+            // the copy below stopped early, it reports why
+            if generated.write_all(&buf[..n]).is_err() {
+                break;
+            }
+            left -= n as u64;
+        }
+    });
+
+    let mut sink_cmd = remote.command("dd", &["of=/dev/null", "bs=64k"]);
+    trace(&[&sink_cmd]);
+    println!(
+        "Streaming {} MiB of {} data.",
+        size >> 20,
+        if zeros { "zeroed" } else { "random" }
+    );
+    let zbak_before = cpu_time(libc::RUSAGE_SELF);
+    let start = Instant::now();
+    // a replayed dd only has its recorded exit status and stderr, the data
+    // goes nowhere
+    #[cfg(feature = "replay")]
+    let replayed = replay::replayed(&sink_cmd);
+    #[cfg(not(feature = "replay"))]
+    let replayed: Option<std::process::Output> = None;
+    let (copied, status, stderr) = match replayed {
+        Some(out) => {
+            let copied = throttle::copy(&mut stream, &mut io::sink(), bwlimit, None, |_| {});
+            drop(stream);
+            generator.join().unwrap();
+            (copied, out.status, out.stderr)
+        }
+        None => {
+            let mut sink = sink_cmd
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let sink_stderr = collect_stderr(&mut sink);
+
+            let mut stdin = sink.stdin.take().unwrap();
+            let copied = throttle::copy(&mut stream, &mut stdin, bwlimit, None, |_| {});
+            drop(stdin);
+            drop(stream);
+            generator.join().unwrap();
+            let status = sink.wait().unwrap();
+            (copied, status, sink_stderr.join().unwrap())
+        }
+    };
+    let elapsed = start.elapsed();
+    #[cfg(feature = "replay")]
+    replay::record(&sink_cmd, status, &[], &stderr);
+
+    if !status.success() {
+        println!("Error: {}", CommandError::new(&sink_cmd, status, &stderr));
+        return;
+    }
+    let copied = match copied {
+        Ok(n) => n,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return,
+        Err(e) => {
+            println!("Error: streaming failed: {}", e);
+            return;
+        }
+    };
+
+    let seconds = elapsed.as_secs_f64();
+    let share = |x: Duration| 100.0 * x.as_secs_f64() / seconds;
+    println!(
+        "{} MiB in {:.1}s: {:.1} MiB/s.",
+        copied >> 20,
+        seconds,
+        copied as f64 / (1 << 20) as f64 / seconds
+    );
+    // a remote dd's CPU time isn't visible from here, only ssh's
+    println!(
+        "CPU: zbak {:.0}% (including generating the data), {} {:.0}%.",
+        share(cpu_time(libc::RUSAGE_SELF) - zbak_before),
+        sink_cmd.get_program().to_string_lossy(),
+        share(cpu_time(libc::RUSAGE_CHILDREN))
+    );
+}
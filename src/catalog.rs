@@ -0,0 +1,109 @@
+use crate::{parse_remote, progress::json_string};
+use std::collections::HashMap;
+
+struct Entry {
+    /// Name without the dataset, e.g. `@2021-06-01T0000`
+    name: String,
+    guid: String,
+    time: chrono::DateTime<chrono::Utc>,
+    /// Whether each location has it, in the order given
+    present: Vec<bool>,
+}
+
+/// Prints every snapshot of `locations`, matched by GUID, with where it
+/// exists: as a table, or as `json` or `csv` for further processing.
+pub fn catalog(locations: &[String], format: &str) {
+    let mut entries: Vec<Entry> = Vec::new();
+    // where each GUID is in `entries`, replicas can have thousands
+    let mut by_guid: HashMap<String, usize> = HashMap::new();
+    for (idx, location) in locations.iter().enumerate() {
+        for snap in parse_remote(location).internal_list("snapshot") {
+            let entry = match by_guid.get(&snap.guid) {
+                Some(&found) => &mut entries[found],
+                None => {
+                    let (_, name) = snap.path.split_once('@').unwrap();
+                    by_guid.insert(snap.guid.clone(), entries.len());
+                    entries.push(Entry {
+                        name: format!("@{}", name),
+                        guid: snap.guid.clone(),
+                        time: snap.time,
+                        present: vec![false; locations.len()],
+                    });
+                    entries.last_mut().unwrap()
+                }
+            };
+            entry.present[idx] = true;
+        }
+    }
+    entries.sort_by_key(|x| x.time);
+
+    match format {
+        "text" => {
+            let width = entries
+                .iter()
+                .map(|x| x.name.len())
+                .chain(std::iter::once("snapshot".len()))
+                .max()
+                .unwrap();
+            println!(
+                "{:width$}  {}",
+                "snapshot",
+                locations.join("  "),
+                width = width
+            );
+            for entry in &entries {
+                let marks = entry
+                    .present
+                    .iter()
+                    .zip(locations)
+                    .map(|(&present, location)| {
+                        let mark = if present { "x" } else { "-" };
+                        format!("{:w$}", mark, w = location.len())
+                    })
+                    .collect::<Vec<_>>();
+                let line = format!("{:width$}  {}", entry.name, marks.join("  "), width = width);
+                println!("{}", line.trim_end());
+            }
+        }
+        "csv" => {
+            println!("snapshot,guid,created,{}", locations.join(","));
+            for entry in &entries {
+                let present = entry
+                    .present
+                    .iter()
+                    .map(|&x| if x { "yes" } else { "no" })
+                    .collect::<Vec<_>>();
+                println!(
+                    "{},{},{},{}",
+                    entry.name,
+                    entry.guid,
+                    entry.time.to_rfc3339(),
+                    present.join(",")
+                );
+            }
+        }
+        "json" => {
+            let rows = entries
+                .iter()
+                .map(|entry| {
+                    let held_by = entry
+                        .present
+                        .iter()
+                        .zip(locations)
+                        .filter(|x| *x.0)
+                        .map(|x| json_string(x.1))
+                        .collect::<Vec<_>>();
+                    format!(
+                        "{{\"snapshot\":{},\"guid\":{},\"created\":{},\"locations\":[{}]}}",
+                        json_string(&entry.name),
+                        json_string(&entry.guid),
+                        json_string(&entry.time.to_rfc3339()),
+                        held_by.join(",")
+                    )
+                })
+                .collect::<Vec<_>>();
+            println!("[{}]", rows.join(",\n "));
+        }
+        other => panic!("unknown format {}, expected text, json or csv", other),
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{progress::json_string, sync_bookmark, Remote};
+use std::collections::{HashMap, HashSet};
+
+/// A snapshot, or a sync bookmark whose snapshot is gone, in the chain of
+/// incremental sends.
+struct Row {
+    name: String,
+    guid: String,
+    time: chrono::DateTime<chrono::Utc>,
+    bookmark: bool,
+    /// Jobs with a sync bookmark here, e.g. `rpi4` or `rpi4 (superseded)`
+    jobs: Vec<String>,
+}
+
+/// Builds the chain of `origin`'s snapshots and the sync bookmarks of
+/// `names`, or of every job if none are given, oldest first.
+fn rows(origin: &Remote, names: &[String]) -> Vec<Row> {
+    let mut rows = origin
+        .internal_list("snapshot")
+        .into_iter()
+        .map(|x| Row {
+            name: x.path[origin.dataset.len()..].to_string(),
+            guid: x.guid,
+            time: x.time,
+            bookmark: false,
+            jobs: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    let bookmarks = origin
+        .internal_list("bookmark")
+        .into_iter()
+        .filter_map(|x| {
+            let job = x.path[sync_bookmark().find(&x.path)?.end()..].to_string();
+            (names.is_empty() || names.contains(&job)).then_some((job, x))
+        })
+        .collect::<Vec<_>>();
+    let mut by_guid = rows
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.guid.clone(), i))
+        .collect::<HashMap<_, _>>();
+    for (i, (job, bookmark)) in bookmarks.iter().enumerate() {
+        // listed oldest first, so a later one of the same job supersedes it
+        let label = if bookmarks[i + 1..].iter().any(|x| &x.0 == job) {
+            format!("{} (superseded)", job)
+        } else {
+            job.clone()
+        };
+        match by_guid.get(&bookmark.guid) {
+            Some(&row) => rows[row].jobs.push(label),
+            None => {
+                by_guid.insert(bookmark.guid.clone(), rows.len());
+                rows.push(Row {
+                    name: bookmark.path[origin.dataset.len()..].to_string(),
+                    guid: bookmark.guid.clone(),
+                    time: bookmark.time,
+                    bookmark: true,
+                    jobs: vec![label],
+                });
+            }
+        }
+    }
+    rows.sort_by_key(|x| x.time);
+    rows
+}
+
+/// Prints the chain of incremental sends from `origin`: its snapshots, the
+/// sync bookmarks each job sends from next, and which of the snapshots
+/// each of `replicas` has, as text or as a graph for Graphviz (dot).
+pub fn chain(origin: &Remote, names: &[String], replicas: &[Remote], format: &str) {
+    let rows = rows(origin, names);
+    let received = replicas
+        .iter()
+        .map(|x| {
+            x.list_snapshots()
+                .into_iter()
+                .map(|x| x.guid)
+                .collect::<HashSet<_>>()
+        })
+        .collect::<Vec<_>>();
+    match format {
+        "text" => print_text(origin, replicas, &rows, &received),
+        "dot" => print_dot(origin, replicas, &rows, &received),
+        other => panic!("unknown format {}, expected text or dot", other),
+    }
+}
+
+fn print_text(origin: &Remote, replicas: &[Remote], rows: &[Row], received: &[HashSet<String>]) {
+    let width = rows
+        .iter()
+        .map(|x| x.name.len() + 2)
+        .chain(std::iter::once(origin.dataset.len()))
+        .max()
+        .unwrap();
+    let mut header = format!("{:width$}", origin.dataset, width = width);
+    for replica in replicas {
+        header += &format!("  {}", replica.dataset);
+    }
+    println!("{}", header.trim_end());
+
+    for (i, row) in rows.iter().enumerate() {
+        // a bookmark can only be sent from, it is off the chain of snapshots
+        let node = if row.bookmark { "  " } else { "o " };
+        let mut line = format!("{:width$}", format!("{}{}", node, row.name), width = width);
+        for (replica, guids) in replicas.iter().zip(received) {
+            let mark = if guids.contains(&row.guid) { "x" } else { "-" };
+            line += &format!("  {:width$}", mark, width = replica.dataset.len());
+        }
+        if !row.jobs.is_empty() {
+            line += &format!("  <- {}", row.jobs.join(", "));
+        }
+        println!("{}", line.trim_end());
+        if !row.bookmark && rows[i + 1..].iter().any(|x| !x.bookmark) {
+            println!("|");
+        }
+    }
+}
+
+fn print_dot(origin: &Remote, replicas: &[Remote], rows: &[Row], received: &[HashSet<String>]) {
+    println!("digraph {} {{", json_string(&origin.dataset));
+    let mut previous = None;
+    for (i, row) in rows.iter().enumerate() {
+        let style = if row.bookmark {
+            ", shape=box, style=dashed"
+        } else {
+            ""
+        };
+        println!("  n{} [label={}{}];", i, json_string(&row.name), style);
+        if !row.bookmark {
+            if let Some(previous) = previous {
+                println!("  n{} -> n{};", previous, i);
+            }
+            previous = Some(i);
+        }
+        for job in &row.jobs {
+            println!(
+                "  {} [shape=note];\n  {} -> n{};",
+                json_string(job),
+                json_string(job),
+                i
+            );
+        }
+    }
+    for (replica, guids) in replicas.iter().zip(received) {
+        // a replica is anchored by the newest snapshot both sides have
+        if let Some(i) = rows.iter().rposition(|x| guids.contains(&x.guid)) {
+            println!(
+                "  {} [shape=cylinder];\n  {} -> n{} [style=dotted];",
+                json_string(&replica.dataset),
+                json_string(&replica.dataset),
+                i
+            );
+        }
+    }
+    println!("}}");
+}
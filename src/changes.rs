@@ -0,0 +1,60 @@
+use crate::Remote;
+use std::collections::BTreeMap;
+
+fn file_type(symbol: &str) -> &str {
+    match symbol {
+        "F" => "files",
+        "/" => "directories",
+        "@" => "symlinks",
+        "B" | "C" => "devices",
+        "=" => "sockets",
+        "|" => "fifos",
+        _ => "other",
+    }
+}
+
+/// Prints a summary of `zfs diff` between two snapshots of the origin,
+/// to spot unexpected mass modifications before they are replicated.
+pub fn print_changes(origin: &Remote, from: &str, to: &str) {
+    let out = match origin.exec(&["diff", "-FH", from, to]) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Warning: cannot list changes: {}", e.trim());
+            return;
+        }
+    };
+
+    let mut by_change = BTreeMap::new();
+    let mut by_type = BTreeMap::new();
+    for line in out.lines() {
+        let parts = line.split('\t').collect::<Vec<_>>();
+        let change = match parts[0] {
+            "+" => "added",
+            "-" => "removed",
+            "M" => "modified",
+            "R" => "renamed",
+            _ => continue,
+        };
+        *by_change.entry(change).or_insert(0) += 1;
+        *by_type.entry(file_type(parts[1])).or_insert(0) += 1;
+    }
+
+    let join = |counts: BTreeMap<&str, u64>| {
+        counts
+            .into_iter()
+            .map(|(k, v)| format!("{} {}", v, k))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    if by_change.is_empty() {
+        println!("No changes since {}.", from);
+    } else {
+        println!(
+            "Changes since {}: {} ({}).",
+            from,
+            join(by_change),
+            join(by_type)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{format_bytes, is_normal_snapshot, Remote};
+
+/// Snapshots looked at to establish what normal churn is.
+const HISTORY: usize = 24;
+
+/// Below this many bytes written per hour a dataset is considered idle and
+/// not worth alerting on.
+const IDLE: f64 = 1024.0 * 1024.0;
+
+/// Warns when the newest snapshot's write rate deviates from the recent
+/// average by more than `factor` in either direction: a sudden spike may be
+/// mass encryption, a sudden drop an application that stopped writing.
+pub fn check_churn(origin: &Remote, factor: f64) {
+    let out = origin
+        .exec(&[
+            "list",
+            "-t",
+            "snapshot",
+            "-d",
+            "1",
+            "-o",
+            "name,creation,written",
+            "-Hp",
+            &origin.dataset,
+        ])
+        .unwrap();
+
+    let snapshots = out
+        .lines()
+        .map(|line| line.split('\t').collect::<Vec<_>>())
+        .filter(|parts| is_normal_snapshot(parts[0]))
+        .map(|parts| {
+            (
+                parts[0].to_string(),
+                parts[1].parse::<i64>().unwrap(),
+                parts[2].parse::<u64>().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // `written` is relative to the previous snapshot, and snapshots are
+    // spaced unevenly after pruning, so compare rates rather than sizes
+    let mut rates = snapshots
+        .windows(2)
+        .map(|pair| {
+            let hours = ((pair[1].1 - pair[0].1).max(1) as f64) / 3600.0;
+            (pair[1].0.as_str(), pair[1].2, pair[1].2 as f64 / hours)
+        })
+        .collect::<Vec<_>>();
+
+    let (name, written, latest) = match rates.pop() {
+        Some(x) => x,
+        None => return,
+    };
+    let history = &rates[rates.len().saturating_sub(HISTORY)..];
+    if history.len() < 4 {
+        return;
+    }
+    let average = history.iter().map(|x| x.2).sum::<f64>() / history.len() as f64;
+    if latest.max(average) < IDLE {
+        return;
+    }
+
+    if latest > average * factor {
+        eprintln!(
+            "Warning: {} wrote {} ({}/h), {:.1}x the recent average of {}/h.",
+            name,
+            format_bytes(written),
+            format_bytes(latest as u64),
+            latest / average,
+            format_bytes(average as u64)
+        );
+    } else if latest * factor < average {
+        eprintln!(
+            "Warning: {} wrote only {} ({}/h), far below the recent average of {}/h.",
+            name,
+            format_bytes(written),
+            format_bytes(latest as u64),
+            format_bytes(average as u64)
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{encryption, Remote};
+
+/// User property marking datasets created by `zbak clone`, so that
+/// `--cleanup` never touches clones made by hand.
+const CLONE_PROPERTY: &str = "zbak:clone";
+
+/// Clones `remote.dataset` (a snapshot) next to its dataset and mounts it
+/// read-only, returning the name of the clone and where it is mounted.
+/// Mounting needs the encryption key, which is loaded from `key` if missing.
+/// Clones of zvols aren't mounted, their block device is returned instead.
+pub fn clone(
+    remote: &Remote,
+    mountpoint: Option<&str>,
+    key: &encryption::KeySource,
+) -> (String, String) {
+    let (dataset, snap) = match remote.dataset.split_once('@') {
+        Some(x) => x,
+        None => panic!("expected <dataset>@<snapshot>, got {}", remote.dataset),
+    };
+    encryption::ensure_key_loaded(remote, dataset, key);
+    let clone_name = match dataset.rsplit_once('/') {
+        Some((parent, name)) => format!("{}/{}-zbak-{}", parent, name, snap),
+        // clones must live in the same pool, so a pool root gets a child
+        None => format!("{}/zbak-{}", dataset, snap),
+    };
+
+    let mut args = vec![
+        "clone".to_string(),
+        "-o".to_string(),
+        "readonly=on".to_string(),
+        "-o".to_string(),
+        format!("{}=on", CLONE_PROPERTY),
+    ];
+    if let Some(mountpoint) = mountpoint {
+        args.push("-o".to_string());
+        args.push(format!("mountpoint={}", mountpoint));
+    }
+    let volume = remote.is_volume(dataset);
+    if volume && mountpoint.is_some() {
+        panic!("{} is a volume and cannot be mounted", dataset);
+    }
+    args.push(remote.dataset.clone());
+    args.push(clone_name.clone());
+
+    println!("Cloning {} to {}.", remote.dataset, clone_name);
+    remote
+        .exec(&args.iter().map(|x| x.as_str()).collect::<Vec<_>>())
+        .unwrap();
+
+    if volume {
+        let device = format!("/dev/zvol/{}", clone_name);
+        println!("Read-only block device at {}.", device);
+        return (clone_name, device);
+    }
+
+    let mounted_at = remote
+        .exec(&["get", "-H", "-o", "value", "mountpoint", &clone_name])
+        .unwrap()
+        .trim()
+        .to_string();
+    println!("Mounted read-only at {}.", mounted_at);
+
+    (clone_name, mounted_at)
+}
+
+/// Lists the clones created by `zbak clone`, of filesystems and zvols,
+/// below `remote.dataset`, or on the whole host if no dataset is given.
+pub fn list_clones(remote: &Remote) -> Vec<String> {
+    let mut args = vec!["list", "-H", "-t", "filesystem,volume", "-o"];
+    let columns = format!("name,{}", CLONE_PROPERTY);
+    args.push(&columns);
+    if !remote.dataset.is_empty() {
+        args.push("-r");
+        args.push(&remote.dataset);
+    }
+
+    remote
+        .exec(&args)
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            let parts = line.split('\t').collect::<Vec<_>>();
+            if parts[1] == "on" {
+                Some(parts[0].to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Destroys every clone created by `zbak clone` below `remote.dataset`, or
+/// on the whole host if no dataset is given.
+pub fn cleanup(remote: &Remote) {
+    let clones = list_clones(remote);
+    if clones.is_empty() {
+        println!("No clones to clean up.");
+    }
+    for clone in clones {
+        println!("Destroying clone {}.", clone);
+        remote.exec(&["destroy", &clone]).unwrap();
+    }
+}
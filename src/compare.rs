@@ -0,0 +1,120 @@
+use crate::{Remote, Snapshot};
+use std::collections::HashMap;
+
+/// Short name of a snapshot or bookmark, e.g. `@2021-06-01T0000`.
+fn short_name(path: &str) -> &str {
+    match path.find(['@', '#']) {
+        Some(idx) => &path[idx..],
+        None => path,
+    }
+}
+
+struct Row {
+    guid: String,
+    time: chrono::DateTime<chrono::Utc>,
+    source: Option<String>,
+    destination: Option<String>,
+}
+
+/// Prints snapshots matched by GUID across both sides, oldest first,
+/// followed by where the two sides diverge.
+pub fn compare(origin: &Remote, destination: &Remote) {
+    let mut rows: Vec<Row> = Vec::new();
+    let mut by_guid: HashMap<String, usize> = HashMap::new();
+
+    let mut add = |snap: Snapshot, on_source: bool| {
+        let idx = match by_guid.get(&snap.guid) {
+            Some(&idx) => idx,
+            None => {
+                by_guid.insert(snap.guid.clone(), rows.len());
+                rows.push(Row {
+                    guid: snap.guid.clone(),
+                    time: snap.time,
+                    source: None,
+                    destination: None,
+                });
+                rows.len() - 1
+            }
+        };
+        let side = if on_source {
+            &mut rows[idx].source
+        } else {
+            &mut rows[idx].destination
+        };
+        // zbak bookmarks share the GUID of their snapshot; prefer showing the snapshot
+        if side.as_deref().is_none_or(|name| name.contains('#')) {
+            *side = Some(short_name(&snap.path).to_string());
+        }
+    };
+
+    for snap in origin.internal_list("snapshot") {
+        add(snap, true);
+    }
+    for snap in origin.internal_list("bookmark") {
+        add(snap, true);
+    }
+    for snap in destination.internal_list("snapshot") {
+        add(snap, false);
+    }
+
+    rows.sort_by_key(|row| row.time);
+
+    let width = rows
+        .iter()
+        .filter_map(|row| row.source.as_ref().map(|x| x.len()))
+        .chain(std::iter::once(origin.dataset.len()))
+        .max()
+        .unwrap();
+
+    println!(
+        "{:width$}  {}",
+        origin.dataset,
+        destination.dataset,
+        width = width
+    );
+    for row in &rows {
+        println!(
+            "{:width$}  {}",
+            row.source.as_deref().unwrap_or("-"),
+            row.destination.as_deref().unwrap_or("-"),
+            width = width
+        );
+    }
+    println!();
+
+    let count = |f: fn(&Row) -> bool| rows.iter().filter(|row| f(row)).count();
+    println!(
+        "{} only on source, {} only on destination, {} on both.",
+        count(|row| row.destination.is_none()),
+        count(|row| row.source.is_none()),
+        count(|row| row.source.is_some() && row.destination.is_some()),
+    );
+
+    let common = rows
+        .iter()
+        .rposition(|row| row.source.is_some() && row.destination.is_some());
+    let common = match common {
+        Some(idx) => idx,
+        None => {
+            println!("No common snapshot, an incremental send is impossible.");
+            return;
+        }
+    };
+    println!(
+        "Newest common snapshot is {} (guid {}).",
+        rows[common].source.as_ref().unwrap(),
+        rows[common].guid
+    );
+
+    let diverged = rows[common + 1..]
+        .iter()
+        .filter_map(|row| row.destination.as_deref())
+        .collect::<Vec<_>>();
+    if !diverged.is_empty() {
+        println!(
+            "Destination diverged after it, with {} snapshot(s) unknown to the source: {}",
+            diverged.len(),
+            diverged.join(", ")
+        );
+    }
+}
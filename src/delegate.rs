@@ -0,0 +1,127 @@
+use crate::{Remote, Transport};
+
+/// Needed on the source by snap (snapshot, destroy, hold/release) and send
+/// (bookmark, send, diff for --changes, recording the destination).
+/// Destroying requires mount.
+const SOURCE_PERMISSIONS: &str = "bookmark,destroy,diff,hold,mount,release,send,snapshot,userprop";
+
+/// Needed on the destination to receive (creating the dataset on the first
+/// send, rolling back with -F), to prune and to record the source's GUID.
+const DESTINATION_PERMISSIONS: &str = "create,destroy,hold,mount,receive,release,rollback,userprop";
+
+fn allow(remote: &Remote, user: &str, permissions: &str, dataset: &str, apply: bool) {
+    println!("# on {}", remote.host());
+    println!("zfs allow -u {} {} {}", user, permissions, dataset);
+    if apply {
+        remote
+            .exec(&["allow", "-u", user, permissions, dataset])
+            .unwrap();
+    }
+}
+
+/// Prints, and with `apply` runs, the `zfs allow` commands that let `user`
+/// run zbak without root.
+pub fn delegate(user: &str, origin: Option<&Remote>, destination: Option<&Remote>, apply: bool) {
+    if let Some(origin) = origin {
+        allow(origin, user, SOURCE_PERMISSIONS, &origin.dataset, apply);
+    }
+    if let Some(destination) = destination {
+        // the first send creates the dataset, so delegate on its parent and
+        // let the permissions be inherited
+        let parent = match destination.dataset.rsplit_once('/') {
+            Some((parent, _)) => parent,
+            None => &destination.dataset,
+        };
+        allow(destination, user, DESTINATION_PERMISSIONS, parent, apply);
+    }
+}
+
+/// The delegations a zfs subcommand needs, as far as zbak runs it.
+fn needed(args: &[&str]) -> Option<&'static str> {
+    Some(match *args.first()? {
+        "snapshot" => "snapshot",
+        "destroy" => "destroy,mount",
+        "bookmark" => "bookmark",
+        "hold" => "hold",
+        "release" => "release",
+        "send" => "send",
+        // -F rolls back, also among other flags as in -uF
+        "receive" | "recv"
+            if args[1..]
+                .iter()
+                .any(|x| x.starts_with('-') && x.contains('F')) =>
+        {
+            "create,mount,receive,rollback"
+        }
+        "receive" | "recv" => "create,mount,receive",
+        "rollback" => "rollback,mount",
+        "diff" => "diff",
+        "clone" => "clone,create,mount",
+        // zbak only sets its own user properties
+        "set" | "inherit" => "userprop",
+        _ => return None,
+    })
+}
+
+/// How PermissionDenied's message starts.
+pub const PERMISSION_DENIED: &str = "permission denied on ";
+
+/// A zfs command refused for lack of a delegation, with what to allow where.
+pub struct PermissionDenied {
+    host: String,
+    user: Option<String>,
+    command: String,
+    permissions: &'static str,
+    dataset: String,
+}
+
+impl PermissionDenied {
+    /// Recognizes zfs's "permission denied" in the `stderr` of `zfs args`
+    /// run on `remote`. ssh's own "Permission denied (publickey)" is left
+    /// alone, no delegation helps with that.
+    pub fn detect(remote: &Remote, args: &[&str], stderr: &str) -> Option<PermissionDenied> {
+        if !stderr
+            .lines()
+            .any(|line| line.trim_end().ends_with(": permission denied"))
+        {
+            return None;
+        }
+        let permissions = needed(args)?;
+        // zbak names the dataset last, as in zfs hold TAG SNAPSHOT
+        let dataset = args.last().unwrap().split(['@', '#']).next().unwrap();
+        // a receive may create the dataset, which needs the parent's
+        // permissions, as in delegate
+        let dataset = match args[0] {
+            "receive" | "recv" => dataset.rsplit_once('/').map_or(dataset, |x| x.0),
+            _ => dataset,
+        };
+        let user = match &remote.transport {
+            Transport::Local => std::env::var("USER").ok(),
+            Transport::SSH(host) => host.target.split_once('@').map(|x| x.0.to_string()),
+        };
+        Some(PermissionDenied {
+            host: remote.host().to_string(),
+            user,
+            command: format!("zfs {}", args.join(" ")),
+            permissions,
+            dataset: dataset.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}: `{}` needs {} on {}. Delegate it there with `zfs allow -u {} {} {}`, or see zbak delegate.",
+            PERMISSION_DENIED,
+            self.host,
+            self.command,
+            self.permissions,
+            self.dataset,
+            self.user.as_deref().unwrap_or("USER"),
+            self.permissions,
+            self.dataset
+        )
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{command_output, shell_quote, trace, CommandError, Remote};
+use chrono::{DateTime, Utc};
+use std::{io::Write, process::Stdio};
+
+/// Runs `program` on `remote`'s host and returns what it printed.
+fn run(remote: &Remote, program: &str, args: &[&str]) -> String {
+    let mut cmd = remote.command(program, args);
+    trace(&[&cmd]);
+    let out = command_output(&mut cmd).unwrap();
+    if !out.status.success() {
+        panic!("{}", CommandError::new(&cmd, out.status, &out.stderr));
+    }
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+/// Writes what a bare-metal restore of `source` needs to know to the file
+/// at `output`, which may be on another host, e.g. next to `replicas`: the
+/// pool's layout and properties, and every property of the dataset and its
+/// children.
+pub fn export(source: &Remote, replicas: &[String], output: &Remote, now: &DateTime<Utc>) {
+    let pool = source.dataset.split('/').next().unwrap();
+    let mut contents = format!(
+        "# zbak dr-export of {} on {}, {}\n",
+        source.dataset,
+        source.host(),
+        now.format("%Y-%m-%d %H:%M UTC")
+    );
+    contents += &format!("[source]\n{}\n", source.dataset);
+    contents += "[replicas]\n";
+    for replica in replicas {
+        contents += &format!("{}\n", replica);
+    }
+    contents += "[pool]\n";
+    contents += &run(source, "zpool", &["status", "-P", pool]);
+    contents += "[pool properties]\n";
+    contents += &run(
+        source,
+        "zpool",
+        &["get", "-H", "-o", "property,value,source", "all", pool],
+    );
+    contents += "[properties]\n";
+    contents += &source
+        .exec(&[
+            "get",
+            "-r",
+            "-H",
+            "-o",
+            "name,property,value,source",
+            "all",
+            &source.dataset,
+        ])
+        .unwrap();
+
+    println!("Writing {} on {}.", output.dataset, output.host());
+    let mut cmd = output.command("tee", &[&output.dataset]);
+    trace(&[&cmd]);
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(contents.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    if !out.status.success() {
+        panic!("{}", CommandError::new(&cmd, out.status, &out.stderr));
+    }
+}
+
+/// The lines of the `[name]` section of a dr-export file.
+fn section<'a>(contents: &'a str, name: &str) -> Vec<&'a str> {
+    let header = format!("[{}]", name);
+    contents
+        .lines()
+        .skip_while(|line| *line != header)
+        .skip(1)
+        .take_while(|line| !(line.starts_with('[') && line.ends_with(']')))
+        .collect()
+}
+
+/// The `zpool create` arguments that rebuild the vdev layout shown in the
+/// config section of `zpool status -P`, e.g. `mirror /dev/sda /dev/sdb`.
+fn layout(status: &[&str]) -> Vec<String> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let mut lines = status
+        .iter()
+        .skip_while(|line| !line.trim_start().starts_with("NAME"))
+        .skip(1);
+    let base = match lines.next() {
+        Some(pool) => indent(pool),
+        None => return Vec::new(),
+    };
+
+    let mut out = Vec::new();
+    // the config ends at the first blank line
+    for line in lines.take_while(|line| !line.trim().is_empty()) {
+        let name = line.split_whitespace().next().unwrap();
+        // groups are numbered, e.g. mirror-0 or draid2:4d:10c:1s-0
+        let group = name
+            .rsplit_once('-')
+            .filter(|(kind, n)| {
+                n.parse::<u32>().is_ok()
+                    && ["mirror", "raidz", "draid"]
+                        .iter()
+                        .any(|x| kind.starts_with(x))
+            })
+            .map(|(kind, _)| kind);
+        let word = match (group, name) {
+            (Some(kind), _) => kind,
+            // classes of vdevs are as far in as the pool
+            (None, "logs") if indent(line) == base => "log",
+            (None, "spares") if indent(line) == base => "spare",
+            (None, name) => name,
+        };
+        out.push(word.to_string());
+    }
+    out
+}
+
+/// Prints the commands that rebuild the pool and dataset described by the
+/// dr-export file at `input` and receive it back from its first replica.
+pub fn plan(input: &Remote) {
+    let contents = run(input, "cat", &[&input.dataset]);
+    let source = match section(&contents, "source").first() {
+        Some(x) => x.to_string(),
+        None => panic!("{} is not a zbak dr-export file", input.dataset),
+    };
+    let pool = source.split('/').next().unwrap();
+
+    if let Some(header) = contents.lines().next() {
+        println!("{}", header);
+    }
+    println!(
+        "# 1. Recreate pool {}; check the devices, their names may have changed.",
+        pool
+    );
+    let mut create = vec!["zpool".to_string(), "create".to_string()];
+    for line in section(&contents, "pool properties") {
+        let parts = line.split('\t').collect::<Vec<_>>();
+        // only ashift can't be changed once the pool exists
+        if parts.len() == 3 && parts[0] == "ashift" && parts[2] == "local" {
+            create.push("-o".to_string());
+            create.push(format!("ashift={}", parts[1]));
+        }
+    }
+    create.push(pool.to_string());
+    create.extend(layout(&section(&contents, "pool")));
+    println!("{}", create.join(" "));
+
+    let properties = section(&contents, "properties")
+        .into_iter()
+        .filter_map(|line| {
+            let parts = line.split('\t').collect::<Vec<_>>();
+            match parts[..] {
+                [name, property, value, "local"] => Some((name, property, value)),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut datasets = properties.iter().map(|x| x.0).collect::<Vec<_>>();
+    datasets.dedup();
+    if !datasets.contains(&source.as_str()) {
+        datasets.insert(0, &source);
+    }
+
+    let replicas = section(&contents, "replicas");
+    match replicas.first() {
+        Some(replica) => {
+            println!(
+                "# 2. Receive the newest snapshots back from {}, assuming children were replicated to the same place below it.",
+                replica
+            );
+            for dataset in &datasets {
+                // the dataset always comes last in a remote
+                let from = format!(
+                    "{}{}",
+                    replica,
+                    dataset.strip_prefix(source.as_str()).unwrap_or("")
+                );
+                println!(
+                    "zbak send --name restore --from {} --to {} --keep 1d --create-dest",
+                    from, dataset
+                );
+            }
+        }
+        None => println!("# 2. Receive each dataset back from its replica, none were recorded."),
+    }
+
+    println!("# 3. Restore properties that were set by hand.");
+    for (name, property, value) in properties {
+        println!("zfs set {}={} {}", property, shell_quote(value), name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        let status = "  pool: tank
+ state: ONLINE
+config:
+
+\tNAME           STATE     READ WRITE CKSUM
+\ttank           ONLINE       0     0     0
+\t  mirror-0     ONLINE       0     0     0
+\t    /dev/sda1  ONLINE       0     0     0
+\t    /dev/sdb1  ONLINE       0     0     0
+\t  raidz2-1     ONLINE       0     0     0
+\t    /dev/sdc1  ONLINE       0     0     0
+\tlogs
+\t  /dev/nvme0n1p1  ONLINE    0     0     0
+\tspares
+\t  /dev/sdd1    AVAIL
+
+errors: No known data errors";
+        assert_eq!(
+            layout(&status.lines().collect::<Vec<_>>()).join(" "),
+            "mirror /dev/sda1 /dev/sdb1 raidz2 /dev/sdc1 log /dev/nvme0n1p1 spare /dev/sdd1"
+        );
+    }
+}
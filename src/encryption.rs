@@ -0,0 +1,158 @@
+use crate::Remote;
+use std::process::Stdio;
+
+/// How to load a missing encryption key.
+pub enum KeySource {
+    /// Fail if the key is not loaded
+    None,
+    /// Let `zfs load-key` use the dataset's keylocation, prompting for the
+    /// passphrase if that is `prompt`
+    Prompt,
+    /// A key file on the dataset's host
+    File(String),
+}
+
+/// Encrypts replicas on receive, for sources that are not encrypted
+/// themselves and so are sent without `-w`.
+pub struct Encryption {
+    pub keylocation: String,
+    pub keyformat: String,
+}
+
+impl Encryption {
+    pub fn new(keylocation: &str, keyformat: &str) -> Encryption {
+        if !keylocation.starts_with("file://") {
+            // recv reads the stream from stdin, so it can't prompt
+            panic!("--encrypt needs a file:// keylocation, got {}", keylocation);
+        }
+        Encryption {
+            keylocation: keylocation.to_string(),
+            keyformat: keyformat.to_string(),
+        }
+    }
+
+    /// Properties for the initial `zfs recv` that creates the dataset.
+    pub fn recv_options(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            "encryption=on".to_string(),
+            "-o".to_string(),
+            format!("keyformat={}", self.keyformat),
+            "-o".to_string(),
+            format!("keylocation={}", self.keylocation),
+        ]
+    }
+}
+
+/// Makes sure the key of `dataset` is available before mounting or reading
+/// from it, loading it from `source` if needed. Unencrypted datasets pass
+/// trivially.
+pub fn ensure_key_loaded(remote: &Remote, dataset: &str, source: &KeySource) {
+    let props = remote
+        .exec(&[
+            "get",
+            "-H",
+            "-o",
+            "value",
+            "encryptionroot,keystatus",
+            dataset,
+        ])
+        .unwrap();
+    let props = props.lines().collect::<Vec<_>>();
+    let (root, status) = (props[0], props[1]);
+    if root == "-" || status == "available" {
+        return;
+    }
+
+    match source {
+        KeySource::None => panic!(
+            "encryption key of {} (root {}) is not loaded, pass --load-key or --keyfile",
+            dataset, root
+        ),
+        KeySource::Prompt => {
+            println!("Loading key for {}.", root);
+            // inherit stdin so the passphrase prompt reaches the user,
+            // even through ssh
+            let mut cmd = remote.cmd(&["load-key", root]);
+            crate::trace(&[&cmd]);
+            let status = cmd.stdin(Stdio::inherit()).status().unwrap();
+            if !status.success() {
+                panic!("cannot load key for {}", root);
+            }
+        }
+        KeySource::File(path) => {
+            println!("Loading key for {} from {}.", root, path);
+            let location = format!("file://{}", path);
+            remote.exec(&["load-key", "-L", &location, root]).unwrap();
+        }
+    }
+}
+
+/// Encryption settings of a source dataset that is sent raw.
+pub struct SourceEncryption {
+    encryption: String,
+    keyformat: String,
+}
+
+/// Returns the encryption settings of `remote`'s dataset, or `None` if it
+/// is not encrypted.
+pub fn source_encryption(remote: &Remote) -> Option<SourceEncryption> {
+    let props = remote
+        .exec(&[
+            "get",
+            "-H",
+            "-o",
+            "value",
+            "encryption,keyformat",
+            &remote.dataset,
+        ])
+        .unwrap();
+    let props = props.lines().collect::<Vec<_>>();
+    if props[0] == "off" {
+        return None;
+    }
+    Some(SourceEncryption {
+        encryption: props[0].to_string(),
+        keyformat: props[1].to_string(),
+    })
+}
+
+fn get(remote: &Remote, props: &str, dataset: &str) -> Vec<String> {
+    remote
+        .exec(&["get", "-H", "-o", "value", props, dataset])
+        .unwrap()
+        .lines()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Checks that `destination`'s dataset is encrypted like the source after
+/// a raw receive. `verify_received` checks the snapshot itself.
+pub fn verify_raw_receive(destination: &Remote, expected: &SourceEncryption) -> Result<(), String> {
+    let props = get(
+        destination,
+        "encryption,keyformat,encryptionroot",
+        &destination.dataset,
+    );
+    if props.len() < 3 {
+        return Err(format!(
+            "cannot tell how {} is encrypted, zfs get printed {:?}",
+            destination.dataset, props
+        ));
+    }
+    if props[0] != expected.encryption || props[1] != expected.keyformat {
+        return Err(format!(
+            "{} has encryption={} keyformat={}, but the source has encryption={} keyformat={}",
+            destination.dataset, props[0], props[1], expected.encryption, expected.keyformat
+        ));
+    }
+    // a raw receive makes the replica its own encryption root
+    if props[2] != destination.dataset {
+        return Err(format!(
+            "{} has encryptionroot {}, expected itself",
+            destination.dataset, props[2]
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use crate::{clone, Remote};
+
+/// Removes what zbak leaves behind on `remote`: sync bookmarks of jobs not
+/// listed in `jobs`, sync bookmarks older than a job's latest one, holds
+/// tagged `release_holds` on snapshots no job sends from next, clones made
+/// by `zbak clone` unless mounted (or with `force`), and the saved state of
+/// interrupted receives. With `dry_run`, only reports what would be
+/// removed.
+pub fn gc(
+    remote: &Remote,
+    jobs: &[String],
+    release_holds: Option<&str>,
+    force: bool,
+    dry_run: bool,
+) {
+    if remote.dataset.is_empty() {
+        panic!("gc needs a dataset");
+    }
+    let re = crate::sync_bookmark();
+
+    // (what, zfs arguments removing it)
+    let mut garbage: Vec<(String, Vec<String>)> = Vec::new();
+
+    let bookmarks = remote.internal_list("bookmark");
+    let mut latest_seen: Vec<String> = Vec::new();
+    // GUIDs of the snapshots the remaining jobs send from next
+    let mut bases: Vec<String> = Vec::new();
+    for bookmark in bookmarks.iter().rev() {
+        let job = match re.find(&bookmark.path) {
+            Some(m) => bookmark.path[m.end()..].to_string(),
+            None => continue,
+        };
+        let why = if !jobs.contains(&job) {
+            format!("job {} is not listed", job)
+        } else if latest_seen.contains(&job) {
+            format!("superseded by a newer bookmark of {}", job)
+        } else {
+            latest_seen.push(job);
+            bases.push(bookmark.guid.clone());
+            continue;
+        };
+        garbage.push((
+            format!("bookmark {} ({})", bookmark.path, why),
+            vec!["destroy".to_string(), bookmark.path.clone()],
+        ));
+    }
+
+    if let Some(tag) = release_holds {
+        let snapshots = remote.internal_list("snapshot");
+        let unreferenced = snapshots
+            .iter()
+            .filter(|x| !bases.contains(&x.guid))
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        for (path, held) in remote.holds(&unreferenced) {
+            if held == tag {
+                garbage.push((
+                    format!("hold {} on {}", tag, path),
+                    vec!["release".to_string(), tag.to_string(), path],
+                ));
+            }
+        }
+    }
+
+    for clone in clone::list_clones(remote) {
+        // a volume's is `-`, destroying one that is open fails below
+        let mounted = remote
+            .exec(&["get", "-H", "-o", "value", "mounted", &clone])
+            .map(|x| x.trim() == "yes")
+            .unwrap_or(false);
+        if mounted && !force {
+            println!(
+                "Keeping clone {}, it is mounted; pass --force to remove it anyway.",
+                clone
+            );
+            continue;
+        }
+        let mut args = vec!["destroy".to_string()];
+        if mounted {
+            args.push("-f".to_string());
+        }
+        args.push(clone.clone());
+        garbage.push((format!("clone {}", clone), args));
+    }
+
+    let tokens = remote
+        .exec(&[
+            "get",
+            "-H",
+            "-r",
+            "-o",
+            "name,value",
+            "receive_resume_token",
+            &remote.dataset,
+        ])
+        .unwrap();
+    for line in tokens.lines() {
+        let (name, token) = line.split_once('\t').unwrap();
+        if token != "-" {
+            garbage.push((
+                format!("partial receive into {}", name),
+                vec!["recv".to_string(), "-A".to_string(), name.to_string()],
+            ));
+        }
+    }
+
+    if garbage.is_empty() {
+        println!("Nothing to clean up.");
+    }
+    for (what, args) in garbage {
+        if dry_run {
+            println!("Would remove {}.", what);
+            continue;
+        }
+        println!("Removing {}.", what);
+        // the rest is still worth cleaning up
+        if let Err(e) = remote.exec(&args.iter().map(|x| x.as_str()).collect::<Vec<_>>()) {
+            println!("Warning: cannot remove {}: {}", what, e);
+        }
+    }
+}
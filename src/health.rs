@@ -0,0 +1,55 @@
+use crate::{command_output, trace, CommandError, Remote};
+
+/// Checks that the pool `remote.dataset` lives in is online and has no known
+/// data errors, going by `zpool status -x`. A pool that is corrupting data
+/// shouldn't be replicated from, nor trusted with a replica.
+pub fn check_pool(remote: &Remote) -> Result<(), String> {
+    let pool = remote.dataset.split('/').next().unwrap();
+    let mut cmd = remote.command("zpool", &["status", "-x", pool]);
+    trace(&[&cmd]);
+    let out = command_output(&mut cmd).unwrap();
+    if !out.status.success() {
+        return Err(CommandError::new(&cmd, out.status, &out.stderr).to_string());
+    }
+
+    // a healthy pool is a single line, "pool 'tank' is healthy"
+    let status = String::from_utf8_lossy(&out.stdout);
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name))
+            .map(str::trim)
+    };
+    let state = field("state:").filter(|x| *x != "ONLINE");
+    let errors = field("errors:")
+        .filter(|x| *x != "No known data errors")
+        .map(|x| x.trim_end_matches(", use '-v' for a list"));
+    let problem = match (state, errors) {
+        (None, None) => return Ok(()),
+        (Some(state), None) => format!("is {}", state),
+        (None, Some(errors)) => format!("has {}", errors),
+        (Some(state), Some(errors)) => format!("is {} with {}", state, errors),
+    };
+    Err(format!("pool {} on {} {}", pool, remote.host(), problem))
+}
+
+/// Whether the pool `remote.dataset` lives in is being scrubbed or
+/// resilvered right now, and if so which of the two.
+pub fn scan_in_progress(remote: &Remote) -> Result<Option<String>, String> {
+    let pool = remote.dataset.split('/').next().unwrap();
+    let mut cmd = remote.command("zpool", &["status", pool]);
+    trace(&[&cmd]);
+    let out = command_output(&mut cmd).unwrap();
+    if !out.status.success() {
+        return Err(CommandError::new(&cmd, out.status, &out.stderr).to_string());
+    }
+
+    // e.g. "scan: resilver in progress since Sun Jun  6 01:00:00 2021"
+    let status = String::from_utf8_lossy(&out.stdout);
+    Ok(status
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("scan:"))
+        .filter(|scan| scan.contains("in progress"))
+        .and_then(|scan| scan.split_whitespace().next())
+        .map(str::to_string))
+}
@@ -0,0 +1,136 @@
+use crate::{format_bytes, state::state_dir};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{path::PathBuf, sync::Mutex, time::Duration};
+
+/// Runs kept in the history, oldest dropped first.
+const KEPT_RUNS: usize = 1000;
+
+/// Runs sending less than this say more about latency than about the link.
+const MIN_BYTES: u64 = 1 << 20;
+
+fn history_path() -> PathBuf {
+    state_dir().join("throughput")
+}
+
+/// Held while the history file is read and replaced.
+static FILE: Mutex<()> = Mutex::new(());
+
+/// One run of a send job, a line of the history file.
+struct Run {
+    time: DateTime<Utc>,
+    job: String,
+    kind: String,
+    bytes: u64,
+    seconds: f64,
+}
+
+impl Run {
+    fn rate(&self) -> f64 {
+        self.bytes as f64 / self.seconds.max(0.001)
+    }
+}
+
+fn read() -> Vec<Run> {
+    let contents = std::fs::read_to_string(history_path()).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            let parts = line.split('\t').collect::<Vec<_>>();
+            match parts[..] {
+                [time, job, kind, bytes, seconds] => Some(Run {
+                    time: Utc.timestamp_opt(time.parse().ok()?, 0).single()?,
+                    job: job.to_string(),
+                    kind: kind.to_string(),
+                    bytes: bytes.parse().ok()?,
+                    seconds: seconds.parse().ok()?,
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Adds a run of `job` that sent `bytes` in `elapsed` to the history in
+/// the state directory. `kind` is full or incremental.
+pub fn record(job: &str, kind: &str, bytes: u64, elapsed: Duration) {
+    if bytes < MIN_BYTES {
+        return;
+    }
+    // parallel sends would otherwise drop each other's runs
+    let _file = FILE.lock().unwrap();
+    let mut runs = read();
+    runs.push(Run {
+        time: Utc::now(),
+        job: job.to_string(),
+        kind: kind.to_string(),
+        bytes,
+        seconds: elapsed.as_secs_f64(),
+    });
+    let runs = &runs[runs.len().saturating_sub(KEPT_RUNS)..];
+    let contents = runs
+        .iter()
+        .map(|x| {
+            format!(
+                "{}\t{}\t{}\t{}\t{:.3}\n",
+                x.time.timestamp(),
+                x.job,
+                x.kind,
+                x.bytes,
+                x.seconds
+            )
+        })
+        .collect::<String>();
+
+    let path = history_path();
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, &path)) {
+        println!("Warning: cannot write {}: {}", path.display(), e);
+    }
+}
+
+fn format_rate(rate: f64) -> String {
+    format!("{}/s", format_bytes(rate as u64))
+}
+
+/// Prints how fast each job's last full and incremental runs sent, and
+/// whether the last incremental run was much slower than the ones before.
+pub fn print_trends() {
+    let runs = read();
+    let mut jobs = runs.iter().map(|x| x.job.as_str()).collect::<Vec<_>>();
+    jobs.sort_unstable();
+    jobs.dedup();
+    if jobs.is_empty() {
+        println!("No send has been recorded on this machine yet.");
+        return;
+    }
+
+    for job in jobs {
+        let mut parts = Vec::new();
+        for kind in ["full", "incremental"] {
+            let of_kind = runs
+                .iter()
+                .filter(|x| x.job == job && x.kind == kind)
+                .collect::<Vec<_>>();
+            let (last, before) = match of_kind.split_last() {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut part = format!(
+                "last {}: {} ({})",
+                kind,
+                format_rate(last.rate()),
+                last.time.format("%Y-%m-%d")
+            );
+            let before = &before[before.len().saturating_sub(10)..];
+            if !before.is_empty() {
+                let average = before.iter().map(|x| x.rate()).sum::<f64>() / before.len() as f64;
+                part += &format!(", {} before", format_rate(average));
+                if last.rate() < average / 2.0 {
+                    part += ", SLOWER";
+                }
+            }
+            parts.push(part);
+        }
+        println!("{}: {}", job, parts.join("; "));
+    }
+}
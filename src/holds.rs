@@ -0,0 +1,29 @@
+use crate::Remote;
+
+/// Places the holds of the source's snapshots on their replicas, matched
+/// by GUID, so that a snapshot pinned on the source isn't pruned from the
+/// replica either. Releases aren't copied, the replica may have holds of
+/// its own with the same tag.
+pub fn replicate_holds(origin: &Remote, destination: &Remote) {
+    let replicas = destination.list_snapshots();
+    let sources = origin
+        .list_snapshots()
+        .into_iter()
+        .filter(|x| replicas.iter().any(|r| r.guid == x.guid))
+        .collect::<Vec<_>>();
+    let wanted = origin.holds(&sources.iter().map(|x| x.path.as_str()).collect::<Vec<_>>());
+    if wanted.is_empty() {
+        return;
+    }
+    let current = destination.holds(&replicas.iter().map(|x| x.path.as_str()).collect::<Vec<_>>());
+
+    for (path, tag) in wanted {
+        let guid = &sources.iter().find(|x| x.path == path).unwrap().guid;
+        let replica = replicas.iter().find(|x| &x.guid == guid).unwrap();
+        if current.contains(&(replica.path.clone(), tag.clone())) {
+            continue;
+        }
+        println!("Holding {} with {}.", replica.path, tag);
+        destination.exec(&["hold", &tag, &replica.path]).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+use crate::shell_quote;
+use std::process::Command;
+
+/// Runs a user's shell command around `snapshot`, telling it the dataset
+/// and snapshot through `ZBAK_DATASET` and `ZBAK_SNAPSHOT`. Hooks run on the
+/// machine running zbak, even for remote datasets.
+pub fn run(what: &str, command: &str, snapshot: &str) -> bool {
+    println!("Running {} hook.", what);
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command])
+        .env("ZBAK_DATASET", snapshot.split_once('@').unwrap().0)
+        .env("ZBAK_SNAPSHOT", snapshot);
+    crate::trace(&[&cmd]);
+    match cmd.status() {
+        Ok(status) => status.success(),
+        Err(e) => {
+            println!("Error: cannot run {} hook: {}", what, e);
+            false
+        }
+    }
+}
+
+/// Pre- and post-snapshot commands for a preset such as `qemu:web`.
+pub fn preset(spec: &str) -> (Option<String>, Option<String>) {
+    let (kind, arg) = match spec.split_once(':') {
+        Some(x) => x,
+        None => panic!("expected <preset>:<argument>, got {}", spec),
+    };
+    let arg = shell_quote(arg);
+    match kind {
+        // a mounted filesystem, e.g. fsfreeze:/var/lib/data
+        "fsfreeze" => (
+            Some(format!("fsfreeze -f {}", arg)),
+            Some(format!("fsfreeze -u {}", arg)),
+        ),
+        // a libvirt domain with qemu-guest-agent running in the guest
+        "qemu" => (
+            Some(format!("virsh domfsfreeze {}", arg)),
+            Some(format!("virsh domfsthaw {}", arg)),
+        ),
+        "docker" => (
+            Some(format!("docker pause {}", arg)),
+            Some(format!("docker unpause {}", arg)),
+        ),
+        // snapshots are crash-consistent already, a checkpoint just
+        // shortens recovery after restoring one
+        "postgres" => (Some(format!("psql {} -c CHECKPOINT", arg)), None),
+        _ => panic!(
+            "unknown hook preset {}, expected fsfreeze, qemu, docker or postgres",
+            kind
+        ),
+    }
+}
@@ -0,0 +1,203 @@
+/// Just enough JSON to read `zfs list -j`. Numbers are kept as written, so
+/// that a 64-bit GUID survives.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in the order they were written
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|x| x.0 == key).map(|x| &x.1),
+            _ => None,
+        }
+    }
+
+    pub fn members(&self) -> &[(String, Value)] {
+        match self {
+            Value::Object(members) => members,
+            _ => &[],
+        }
+    }
+
+    /// A string or number as text, e.g. a property's value.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Value::String(x) | Value::Number(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.input[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(format!("expected {} at offset {}", token, self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Value::String),
+            Some('t') => self.expect("true").map(|_| Value::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some('n') => self.expect("null").map(|_| Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let rest = &self.input[self.pos..];
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                Ok(Value::Number(rest[..len].to_string()))
+            }
+            _ => Err(format!("unexpected input at offset {}", self.pos)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect("{")?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(format!("expected , or }} at offset {}", self.pos)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(format!("expected , or ] at offset {}", self.pos)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut out = String::new();
+        let mut chars = self.input[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|x| x.1) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| chars.next().map(|x| x.1));
+                        let code = u32::from_str_radix(&hex.collect::<String>(), 16)
+                            .map_err(|_| format!("bad \\u escape at offset {}", self.pos + i))?;
+                        // surrogate pairs don't occur in dataset names
+                        out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    Some(c) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
+
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut parser = Parser { input, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != input.len() {
+        return Err(format!("trailing input at offset {}", parser.pos));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zfs_list() {
+        let value = parse(
+            r#"{"datasets": {"tank/a@b\tc": {"createtxg": "9",
+              "properties": {"guid": {"value": 18446744073709551615}},
+              "flags": [true, null, "é"]}}}"#,
+        )
+        .unwrap();
+        let (name, dataset) = &value.get("datasets").unwrap().members()[0];
+        assert_eq!(name, "tank/a@b\tc");
+        let guid = dataset.get("properties").unwrap().get("guid").unwrap();
+        assert_eq!(
+            guid.get("value").unwrap().text(),
+            Some("18446744073709551615")
+        );
+        assert_eq!(
+            dataset.get("flags"),
+            Some(&Value::Array(vec![
+                Value::Bool(true),
+                Value::Null,
+                Value::String("é".to_string())
+            ]))
+        );
+        assert!(parse(r#"{"a": 1,}"#).is_err());
+    }
+}
@@ -1,26 +1,112 @@
 use chrono::{Datelike, Duration, DurationRound, TimeZone, Utc};
 use clap::Clap;
 use std::{
+    io::{BufRead, Read},
     ops::Sub,
-    process::{Command, Stdio},
+    panic,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
 };
 
+mod audit;
+mod bench;
+mod catalog;
+mod chain;
+mod changes;
+mod churn;
+mod clone;
+mod compare;
+mod delegate;
+mod dr;
+mod encryption;
+mod gc;
+mod health;
+mod history;
+mod holds;
+mod hooks;
+mod json;
+mod man;
+mod platform;
+mod progress;
+mod properties;
+#[cfg(feature = "replay")]
+mod replay;
+mod restore;
+mod select;
+mod selftest;
+mod signal;
+mod state;
+mod status;
+mod summary;
+mod throttle;
+
+/// Creates, prunes and replicates zfs snapshots
 #[derive(Clap)]
 #[clap(
     setting = clap::AppSettings::ColoredHelp,
     setting = clap::AppSettings::DeriveDisplayOrder,
     setting = clap::AppSettings::VersionlessSubcommands,
 )]
-
 pub struct App {
+    /// Wait for another running zbak instance instead of exiting, and for
+    /// a receive into send's destination that is still running
+    #[clap(long = "wait", global = true)]
+    wait: bool,
+    /// Give up on zfs commands other than send/recv after this long, e.g. 5m
+    #[clap(long = "timeout", global = true)]
+    timeout: Option<String>,
+    /// Reach ssh remotes through this jump host, as with ssh -J
+    #[clap(long = "ssh-jump", global = true)]
+    ssh_jump: Option<String>,
+    /// Command used instead of ssh to reach remotes, e.g. "ssh -p 2222"
+    #[clap(long = "rsh", global = true)]
+    rsh: Option<String>,
+    /// Print every command before running it, ready to be pasted into a shell
+    #[clap(long = "trace", global = true)]
+    trace: bool,
+    /// Never destroy snapshots whose name matches this glob, e.g.
+    /// 'migration-*', may be repeated
+    #[clap(long = "protect", global = true, number_of_values = 1)]
+    protect: Vec<String>,
+    /// Write a JSON summary of the run's jobs here when it ends, e.g.
+    /// /run/zbak/last.json
+    #[clap(long = "summary-file", global = true)]
+    summary_file: Option<String>,
+    /// The operating system of a remote host, e.g. nas.local=freebsd, if
+    /// it can't be asked with uname, e.g. because its ssh key only allows
+    /// zfs; may be repeated
+    #[clap(long = "platform", global = true, number_of_values = 1)]
+    platform: Vec<String>,
     #[clap(subcommand)]
     subcmd: Subcommand,
 }
 
+// parsed once per run, the size of the send variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clap)]
 enum Subcommand {
     Snap(SnapCommand),
     Send(SendCommand),
+    Compare(CompareCommand),
+    Chain(ChainCommand),
+    Clone(CloneCommand),
+    RestoreFile(RestoreFileCommand),
+    Watch(WatchCommand),
+    Delegate(DelegateCommand),
+    Selftest(SelftestCommand),
+    Man(ManCommand),
+    Gc(GcCommand),
+    PruneRemote(PruneRemoteCommand),
+    Bench(BenchCommand),
+    Audit(AuditCommand),
+    Catalog(CatalogCommand),
+    Status(StatusCommand),
+    DrExport(DrExportCommand),
+    DrPlan(DrPlanCommand),
 }
 
 /// Replicates snapshots
@@ -33,22 +119,614 @@ pub struct SendCommand {
     from: String,
     #[clap(long = "to")]
     to: String,
+    /// Also send each dataset below --from to the same place below --to
+    #[clap(long = "recursive")]
+    recursive: bool,
+    /// With --recursive, send up to this many datasets at once; a dataset
+    /// still waits for the one it is below
+    #[clap(long = "jobs", default_value = "1")]
+    jobs: usize,
+    /// With --recursive, skip datasets matching this glob and those below
+    /// them, e.g. 'tank/home/*/cache' or '**/tmp'; may be repeated
+    #[clap(long = "exclude", number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Snapshots to keep, e.g. 7d24h4f
+    #[clap(long = "keep")]
+    keep: String,
+    /// Only send during this local time window, e.g. 01:00-06:00
+    #[clap(long = "window")]
+    window: Option<String>,
+    /// Limit transfer rate, e.g. 5M or 5M@08:00-18:00,20M
+    #[clap(long = "bwlimit")]
+    bwlimit: Option<String>,
+    /// Release holds with this tag on snapshots being pruned
+    #[clap(long = "release-holds")]
+    release_holds: Option<String>,
+    /// Summarize files changed since the last sync
+    #[clap(long = "changes")]
+    changes: bool,
+    /// Write JSON-lines progress events to fd:N, unix:PATH, or a file
+    #[clap(long = "progress-json")]
+    progress_json: Option<String>,
+    /// Abort transfers still running this long after send started, e.g. 4h
+    #[clap(long = "deadline")]
+    deadline: Option<String>,
+    /// Start no further increments this long after send started, e.g. 4h;
+    /// the next run continues where this one stopped
+    #[clap(long = "max-runtime")]
+    max_runtime: Option<String>,
+    /// Send unencrypted and encrypt new replicas with the key at this
+    /// keylocation, e.g. file:///etc/zbak/key
+    #[clap(long = "encrypt")]
+    encrypt: Option<String>,
+    /// keyformat of the key given to --encrypt
+    #[clap(long = "keyformat", default_value = "passphrase")]
+    keyformat: String,
+    /// Delay the start by up to this long, e.g. 10m, so that many machines
+    /// sending to one server at the same time are spread out
+    #[clap(long = "jitter")]
+    jitter: Option<String>,
+    /// Run zfs send and recv with this niceness, e.g. 10
+    #[clap(long = "nice")]
+    nice: Option<i32>,
+    /// Run zfs send and recv in this ionice class, e.g. idle
+    #[clap(long = "ionice")]
+    ionice: Option<String>,
+    /// Copy these properties to the replica after each sync, e.g.
+    /// quota,compression
+    #[clap(long = "sync-props")]
+    sync_props: Option<String>,
+    /// Place the holds of the source's snapshots on their replicas after
+    /// each sync, so that pinned snapshots aren't pruned there; releases
+    /// aren't copied
+    #[clap(long = "replicate-holds")]
+    replicate_holds: bool,
+    /// Create the destination dataset with a full send if it doesn't exist,
+    /// instead of refusing to
+    #[clap(long = "create-dest")]
+    create_dest: bool,
+    /// Property for a destination created by --create-dest, e.g.
+    /// compression=lz4, may be repeated
+    #[clap(long = "dest-property", number_of_values = 1)]
+    dest_properties: Vec<String>,
+    /// Send even if the source is not the dataset the destination was
+    /// replicated from, e.g. after recreating it
+    #[clap(long = "replace-dest")]
+    replace_dest: bool,
+    /// Receive even if the destination was written to since its newest
+    /// snapshot, discarding those changes
+    #[clap(long = "force")]
+    force: bool,
+    /// Check both pools with zpool status -x first and abort, or only warn,
+    /// if either is degraded or has data errors: abort or warn
+    #[clap(long = "pool-health")]
+    pool_health: Option<String>,
+    /// Skip the run if either pool is being scrubbed or resilvered and more
+    /// than this would be sent, e.g. 10G; 0 skips every send during a scan
+    #[clap(long = "defer-during-scan")]
+    defer_during_scan: Option<String>,
+    /// Extra options for zfs send, e.g. --send-opt=--holds, may be repeated
+    #[clap(long = "send-opt", number_of_values = 1, allow_hyphen_values = true)]
+    send_opts: Vec<String>,
+    /// Extra options for zfs recv, e.g. --recv-opt '-o compression=lz4',
+    /// may be repeated
+    #[clap(long = "recv-opt", number_of_values = 1, allow_hyphen_values = true)]
+    recv_opts: Vec<String>,
+}
+/// Shows the chain of incremental sends from a dataset and where each job
+/// and replica is anchored to it
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct ChainCommand {
+    /// Source dataset, e.g. zroot/code
+    location: String,
+    /// Only show the sync bookmarks of this job, may be repeated
+    #[clap(long = "name", number_of_values = 1)]
+    names: Vec<String>,
+    /// Also show which snapshots this replica has, may be repeated
+    #[clap(long = "to", number_of_values = 1)]
+    replicas: Vec<String>,
+    /// Output format: text, or dot for Graphviz
+    #[clap(long = "format", default_value = "text")]
+    format: String,
+}
+/// Lists snapshots on either side of a replication, matched by GUID
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct CompareCommand {
+    #[clap(long = "from")]
+    from: String,
+    #[clap(long = "to")]
+    to: String,
+}
+/// Clones a snapshot to a read-only dataset for browsing
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct CloneCommand {
+    /// Snapshot to clone, e.g. rpi4.local:rpool/code@2021-06-01T0000
+    target: Option<String>,
+    mountpoint: Option<String>,
+    /// Destroy clones made by zbak below the target instead
+    #[clap(long = "cleanup")]
+    cleanup: bool,
+    #[clap(flatten)]
+    key: KeyArgs,
+}
+/// Restores a file or directory from a snapshot
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct RestoreFileCommand {
+    /// What to restore, e.g. rpi4.local:rpool/code@2021-06-01T0000:src/main.rs
+    source: String,
+    /// Local directory to restore into
+    dest: String,
+    /// Use the newest snapshot taken at or before this local time, e.g.
+    /// "2024-04-01 09:00", leaving the snapshot out of the source as in
+    /// rpool/code@:src/main.rs
+    #[clap(long = "at")]
+    at: Option<String>,
+    /// Also look for that snapshot on this replica of the dataset
+    #[clap(long = "replica", number_of_values = 1)]
+    replica: Vec<String>,
+    #[clap(flatten)]
+    key: KeyArgs,
+}
+// How to load encryption keys of datasets that need mounting
+#[derive(Clap)]
+pub struct KeyArgs {
+    /// Prompt for the passphrase if the encryption key isn't loaded
+    #[clap(long = "load-key")]
+    load_key: bool,
+    /// Load a missing encryption key from this file on the dataset's host
+    #[clap(long = "keyfile")]
+    keyfile: Option<String>,
+}
+
+impl KeyArgs {
+    fn source(&self) -> encryption::KeySource {
+        match &self.keyfile {
+            Some(path) => encryption::KeySource::File(path.clone()),
+            None if self.load_key => encryption::KeySource::Prompt,
+            None => encryption::KeySource::None,
+        }
+    }
+}
+/// Follows the progress of a running zbak
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct WatchCommand {}
+/// Prunes a replica's snapshots without sending to it
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct PruneRemoteCommand {
+    #[clap(long = "to")]
+    to: String,
+    /// Snapshots to keep, e.g. 6m4w7d
+    #[clap(long = "keep")]
+    keep: String,
+    /// Release holds with this tag on snapshots being pruned
+    #[clap(long = "release-holds")]
+    release_holds: Option<String>,
+    /// Only print what would be pruned
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+/// Measures how fast data streams to a remote, without touching zfs
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct BenchCommand {
+    /// Remote to stream to, e.g. rpi4.local:rpool; nothing is written there.
+    /// Without it, only the local pipeline is measured
+    #[clap(long = "to")]
+    to: Option<String>,
+    /// How much data to stream, e.g. 512M
+    #[clap(long = "size", default_value = "1G")]
+    size: String,
+    /// Stream zeros instead of random data, to see what ssh compression
+    /// (e.g. -C in ZBAK_SSH_OPTS) gains at best
+    #[clap(long = "zeros")]
+    zeros: bool,
+    /// Limit transfer rate, e.g. 5M or 5M@08:00-18:00,20M
+    #[clap(long = "bwlimit")]
+    bwlimit: Option<String>,
+}
+/// Reports where snapshots fall short of a keep specification
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct AuditCommand {
+    /// Datasets to check, e.g. zroot/code rpi4.local:rpool/code
+    #[clap(required = true)]
+    location: Vec<String>,
+    /// Snapshots each dataset should have, e.g. 7d24h4f
     #[clap(long = "keep")]
     keep: String,
 }
+/// Lists which snapshots exist where, matched by GUID across datasets
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct CatalogCommand {
+    /// The source and its replicas, e.g. zroot/code rpi4.local:rpool/code
+    #[clap(required = true)]
+    location: Vec<String>,
+    /// Output format: text, json or csv
+    #[clap(long = "format", default_value = "text")]
+    format: String,
+}
+/// Shows how fresh the newest snapshot of each dataset is, listing all of
+/// them in parallel
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct StatusCommand {
+    /// Datasets to check, sources and replicas alike, e.g. zroot/code
+    /// rpi4.local:rpool/code
+    #[clap(required = true)]
+    location: Vec<String>,
+    /// Flag datasets whose newest snapshot is older than this, e.g. 1d,
+    /// and exit with status 1
+    #[clap(long = "max-age")]
+    max_age: Option<String>,
+    /// Output format: text, or zabbix (for zabbix_sender -i -) or checkmk
+    /// (a local check)
+    #[clap(long = "format", default_value = "text")]
+    format: String,
+    /// Also show how fast each send job's recent runs from this machine
+    /// were, to spot a link or disk getting slower
+    #[clap(long = "throughput")]
+    throughput: bool,
+}
+/// Saves the pool layout and properties a bare-metal restore of a dataset
+/// needs, e.g. next to its replica
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct DrExportCommand {
+    /// Dataset to describe, e.g. zroot/code
+    source: String,
+    /// Where the dataset is replicated to, e.g. rpi4.local:rpool/code, may
+    /// be repeated
+    #[clap(long = "replica", number_of_values = 1)]
+    replicas: Vec<String>,
+    /// File to write, e.g. rpi4.local:/srv/zbak/zroot-code.dr
+    #[clap(long = "output")]
+    output: String,
+}
+/// Prints the commands that restore a dataset from a dr-export file
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct DrPlanCommand {
+    /// File written by dr-export, e.g. rpi4.local:/srv/zbak/zroot-code.dr
+    input: String,
+}
+/// Removes stale bookmarks, clones and partial receives left by zbak
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct GcCommand {
+    /// Dataset to clean up, e.g. zroot/code or rpi4.local:rpool/code
+    location: String,
+    /// Name of a job still sending from this dataset, may be repeated; the
+    /// bookmarks of all other jobs are removed
+    #[clap(long = "name", number_of_values = 1)]
+    names: Vec<String>,
+    /// Without --name, remove the bookmarks of every job, as all of them
+    /// were retired
+    #[clap(long = "all-retired")]
+    all_retired: bool,
+    /// Release holds with this tag on snapshots that no remaining job sends
+    /// from next, as send --release-holds does when pruning
+    #[clap(long = "release-holds")]
+    release_holds: Option<String>,
+    /// Also remove clones of zbak clone that are mounted
+    #[clap(long = "force")]
+    force: bool,
+    /// Only print what would be removed
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+}
+/// Writes man pages for zbak and its commands
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct ManCommand {
+    /// Directory to write the pages to
+    dir: String,
+}
+/// Runs snap, send and restore-file against throwaway file-backed pools,
+/// needs root
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct SelftestCommand {}
+/// Prints the zfs permissions needed to run zbak unprivileged
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+pub struct DelegateCommand {
+    #[clap(long = "user")]
+    user: String,
+    #[clap(long = "from")]
+    from: Option<String>,
+    #[clap(long = "to")]
+    to: Option<String>,
+    /// Run the zfs allow commands instead of only printing them
+    #[clap(long = "apply")]
+    apply: bool,
+}
 /// Creates and prunes snapshots
 #[derive(Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 pub struct SnapCommand {
-    location: String,
+    /// Datasets to snapshot, or globs such as `tank/vm/*` (`**` matches
+    /// nested datasets too)
+    #[clap(required = true)]
+    location: Vec<String>,
+    /// Snapshot all matching datasets in one atomic `zfs snapshot`, so that
+    /// an application spread over several datasets is captured at a single
+    /// point in time; they must share a pool
+    #[clap(long = "group")]
+    group: bool,
+    /// Skip datasets matching this glob and those below them, may be
+    /// repeated
+    #[clap(long = "exclude", number_of_values = 1)]
+    exclude: Vec<String>,
+    /// Snapshot and prune even if the newest snapshot is from the future,
+    /// which means this machine's or the remote's clock is off
+    #[clap(long = "allow-clock-skew")]
+    allow_clock_skew: bool,
+    /// How new snapshots are named: minutes (2021-06-01T0000), seconds
+    /// (2021-06-01T000000) or compact (20210601-0000); all are recognized
+    #[clap(long = "name-format", default_value = "minutes")]
+    name_format: String,
+    /// Add this label to the new snapshot's name, e.g. pre-upgrade for
+    /// 2021-06-01T0000-pre-upgrade; it is taken however recent the last
+    /// snapshot is, and kept by the l count of --keep instead of a slot
+    #[clap(long = "label")]
+    label: Option<String>,
+    /// Stop at the first dataset that fails instead of continuing with the
+    /// others
+    #[clap(long = "fail-fast")]
+    fail_fast: bool,
+    /// Retry failed datasets this many times once the others are done
+    #[clap(long = "retries", default_value = "1")]
+    retries: u32,
+    /// How long to wait before retrying, e.g. 30s or 5m
+    #[clap(long = "retry-delay", default_value = "30s")]
+    retry_delay: String,
+    /// Snapshots to keep, e.g. 7d24h4f
     #[clap(long = "keep")]
     keep: String,
+    /// Release holds with this tag on snapshots being pruned
+    #[clap(long = "release-holds")]
+    release_holds: Option<String>,
+    /// Only prune snapshots that the send job of this name has
+    /// replicated, i.e. that are no newer than its sync bookmark; may be
+    /// repeated
+    #[clap(long = "replicated-by", number_of_values = 1)]
+    replicated_by: Vec<String>,
+    /// Only prune snapshots that send jobs have replicated to at least this
+    /// many distinct destinations
+    #[clap(long = "min-copies", default_value = "0")]
+    min_copies: usize,
+    /// Warn when the new snapshot's write rate is this many times above or
+    /// below the recent average
+    #[clap(long = "churn-alert")]
+    churn_alert: Option<f64>,
+    /// Shell command run before each snapshot, e.g. to freeze a VM's
+    /// filesystems; the snapshot is skipped if it fails
+    #[clap(long = "pre-snapshot")]
+    pre_snapshot: Option<String>,
+    /// Shell command run after each snapshot, even if taking it failed
+    #[clap(long = "post-snapshot")]
+    post_snapshot: Option<String>,
+    /// Hooks for a common application instead of --pre-snapshot and
+    /// --post-snapshot: fsfreeze:PATH, qemu:DOMAIN, docker:CONTAINER or
+    /// postgres:DBNAME
+    #[clap(long = "hook")]
+    hook: Option<String>,
+}
+
+/// Timeout in seconds for control commands, or 0 for none. Set once from
+/// `--timeout`.
+static COMMAND_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Like `Command::output`, but kills the command and returns `None` if it
+/// takes longer than `timeout`.
+fn output_with_timeout(mut cmd: Command, timeout: std::time::Duration) -> Option<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // drain both pipes so that a chatty command can't block on a full pipe
+    let drain = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).ok();
+            buf
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().unwrap()));
+    let stderr = drain(Box::new(child.stderr.take().unwrap()));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            child.kill().ok();
+            child.wait().unwrap();
+            return None;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+
+    Some(Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
+    })
+}
+
+/// Set once from `--trace`.
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Quotes `word` for a POSIX shell, leaving harmless words alone.
+fn shell_quote(word: &str) -> String {
+    let safe = |ch: char| ch.is_ascii_alphanumeric() || "@%+=:,./_-".contains(ch);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// `program` and `args` as one shell command line. ssh hands the remote
+/// shell a single string, so each word must be quoted to arrive intact.
+fn remote_command(program: &str, args: &[&str]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().copied())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|x| shell_quote(&x.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// With `--trace`, prints the commands about to run, joined into a
+/// pipeline if there are several.
+fn trace(cmds: &[&Command]) {
+    if TRACE.load(Ordering::Relaxed) {
+        let line = cmds.iter().map(|x| command_line(x)).collect::<Vec<_>>();
+        eprintln!("+ {}", line.join(" | "));
+    }
+}
+
+/// Patterns from `--protect`, matched against snapshot names.
+static PROTECTED: OnceLock<Vec<regex::Regex>> = OnceLock::new();
+
+fn is_protected(path: &str) -> bool {
+    let name = path.split_once('@').map_or(path, |x| x.1);
+    PROTECTED
+        .get()
+        .is_some_and(|patterns| patterns.iter().any(|x| x.is_match(name)))
+}
+
+/// How ssh transports connect. Set once from the command line.
+#[derive(Default)]
+struct SshSettings {
+    jump: Option<String>,
+    /// Replaces the whole ssh command when not empty
+    rsh: Vec<String>,
+    /// Where the sockets of shared ssh connections are kept
+    control_dir: Option<std::path::PathBuf>,
+}
+
+static SSH_SETTINGS: OnceLock<SshSettings> = OnceLock::new();
+
+/// Hosts a shared connection was started for, see `start_master`.
+static MASTERS: Mutex<Vec<SshHost>> = Mutex::new(Vec::new());
+
+/// The socket of the connection to `host` that ssh commands share.
+fn control_path(host: &SshHost, settings: &SshSettings) -> Option<std::path::PathBuf> {
+    let name = format!("ssh-{}-{}", host.target, host.port.unwrap_or(22));
+    settings.control_dir.as_ref().map(|x| x.join(name))
+}
+
+/// ssh with zbak's options for reaching `host`, up to the host itself.
+fn ssh(host: &SshHost, settings: &SshSettings) -> Command {
+    let mut tmp = std::process::Command::new("ssh");
+    if let Some(port) = host.port {
+        tmp.args(["-p", &port.to_string()]);
+    }
+    // ssh uses the first value given for an option, so these
+    // take precedence over the defaults below
+    if let Ok(opts) = std::env::var("ZBAK_SSH_OPTS") {
+        tmp.args(opts.split_whitespace());
+    }
+    if let Some(jump) = &settings.jump {
+        tmp.args(["-J", jump]);
+    }
+    if let Some(path) = control_path(host, settings) {
+        tmp.arg("-o");
+        tmp.arg(format!("ControlPath={}", path.display()));
+    }
+    // keepalives make a dead connection fail the transfer
+    // instead of leaving it hanging behind a NAT gateway
+    tmp.args([
+        "-o",
+        "ConnectTimeout=20",
+        "-o",
+        "ServerAliveInterval=15",
+        "-o",
+        "ServerAliveCountMax=4",
+    ]);
+    tmp
+}
+
+/// Opens a connection to `host` in the background that later ssh commands
+/// share, so that the many small zfs commands of a run don't each pay for
+/// a handshake. It closes itself a minute after the last use. Without it,
+/// e.g. if it can't be started, ssh simply connects on its own.
+fn start_master(host: &SshHost, settings: &SshSettings) {
+    if settings.control_dir.is_none() {
+        return;
+    }
+    // held until the master is up, so that a parallel send to the same
+    // host doesn't connect without it, or remove its socket
+    let mut masters = MASTERS.lock().unwrap();
+    if masters.contains(host) {
+        return;
+    }
+    masters.push(host.clone());
+    let quietly = |mut cmd: Command| {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command_output(&mut cmd).is_ok_and(|x| x.status.success())
+    };
+    // one may be left from a run less than a minute ago
+    let mut check = ssh(host, settings);
+    check.args(["-O", "check", &host.target]);
+    if quietly(check) {
+        return;
+    }
+    // a socket left behind by a master that died would block a new one
+    if let Some(path) = control_path(host, settings) {
+        std::fs::remove_file(path).ok();
+    }
+
+    let mut cmd = ssh(host, settings);
+    cmd.args([
+        "-o",
+        "ControlMaster=yes",
+        "-o",
+        "ControlPersist=60",
+        "-N",
+        "-f",
+        &host.target,
+    ]);
+    trace(&[&cmd]);
+    // the backgrounded master must not hold on to pipes of ours
+    quietly(cmd);
+}
+
+/// An ssh destination, `host` or `user@host`, with the port if one was
+/// given.
+#[derive(Debug, Clone, PartialEq)]
+struct SshHost {
+    target: String,
+    port: Option<u16>,
 }
 
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq)]
 enum Transport {
     Local,
-    SSH(String),
+    SSH(SshHost),
 }
 
 struct Remote {
@@ -60,47 +738,248 @@ struct Remote {
 struct Snapshot {
     path: String,
     time: chrono::DateTime<Utc>,
+    guid: String,
+}
+
+/// Compiled once, they are matched against every snapshot listed.
+static NORMAL_SNAPSHOT: OnceLock<regex::Regex> = OnceLock::new();
+static SYNC_BOOKMARK: OnceLock<regex::Regex> = OnceLock::new();
+static LABELED_SNAPSHOT: OnceLock<regex::Regex> = OnceLock::new();
+static LABEL: OnceLock<regex::Regex> = OnceLock::new();
+/// One per entry of `NAME_FORMATS`, in the same order.
+static NAME_TIMES: OnceLock<Vec<regex::Regex>> = OnceLock::new();
+
+/// Formats `snap --name-format` may name snapshots with: (name, chrono
+/// format, regex). They must not be mistaken for one another, and each
+/// carries the whole minute so that names sort like times.
+const NAME_FORMATS: &[(&str, &str, &str)] = &[
+    ("minutes", "%Y-%m-%dT%H%M", r"\d{4}-\d{2}-\d{2}T\d{4}"),
+    ("seconds", "%Y-%m-%dT%H%M%S", r"\d{4}-\d{2}-\d{2}T\d{6}"),
+    ("compact", "%Y%m%d-%H%M", r"\d{8}-\d{4}"),
+];
+
+/// The chrono format of a `--name-format` choice.
+fn name_format(name: &str) -> &'static str {
+    match NAME_FORMATS.iter().find(|x| x.0 == name) {
+        Some(x) => x.1,
+        None => panic!(
+            "unknown name format {}, expected one of {}",
+            name,
+            NAME_FORMATS
+                .iter()
+                .map(|x| x.0)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// A regex matching a snapshot name in any of the formats, without its
+/// label.
+fn name_pattern() -> String {
+    let alternatives = NAME_FORMATS.iter().map(|x| x.2).collect::<Vec<_>>();
+    format!("(?:{})", alternatives.join("|"))
+}
+
+/// The characters zfs allows in a dataset's name, which never include the
+/// `@` and `#` that start a snapshot's or bookmark's.
+const DATASET_PATTERN: &str = r"[A-Za-z0-9_.: /%-]+";
+
+/// What `snap --label` may append to a name, e.g. `pre-upgrade` in
+/// `2024-05-01T0130-pre-upgrade`. Starting with a letter keeps it apart
+/// from the digits of the compact format.
+const LABEL_PATTERN: &str = r"[a-z][a-z0-9_.]*(?:-[a-z0-9_.]+)*";
+
+/// Checks a `--label` before any snapshot is named with it.
+fn check_label(label: &str) {
+    let re = LABEL.get_or_init(|| regex::Regex::new(&format!("^{}$", LABEL_PATTERN)).unwrap());
+    // it would be mistaken for the job in a sync bookmark's name
+    if !re.is_match(label) || label.split('-').any(|x| x == "sync") {
+        panic!(
+            "invalid label {}, expected lowercase words such as pre-upgrade, other than sync",
+            label
+        );
+    }
+}
+
+/// The label of a snapshot taken with `snap --label`, e.g. `pre-upgrade`.
+fn snapshot_label(path: &str) -> Option<&str> {
+    LABELED_SNAPSHOT
+        .get_or_init(|| {
+            regex::Regex::new(&format!(
+                r"^{}@{}-({})$",
+                DATASET_PATTERN,
+                name_pattern(),
+                LABEL_PATTERN
+            ))
+            .unwrap()
+        })
+        .captures(path)
+        .map(|caps| caps.get(1).unwrap().as_str())
+}
+
+/// The time a snapshot name such as `zroot/code@2021-06-01T0000` stands
+/// for, if it is in one of the formats. Labels and job names after it are
+/// ignored.
+fn time_from_name(path: &str) -> Option<chrono::DateTime<Utc>> {
+    let name = path.split_once(['@', '#'])?.1;
+    let patterns = NAME_TIMES.get_or_init(|| {
+        NAME_FORMATS
+            .iter()
+            // the dash keeps minutes from matching the start of seconds
+            .map(|x| regex::Regex::new(&format!("^({})(?:-|$)", x.2)).unwrap())
+            .collect()
+    });
+    NAME_FORMATS
+        .iter()
+        .zip(patterns)
+        .find_map(|((_, format, _), re)| {
+            let date = re.captures(name)?.get(1).unwrap().as_str();
+            chrono::NaiveDateTime::parse_from_str(date, format)
+                .ok()
+                .map(|x| Utc.from_utc_datetime(&x))
+        })
+}
+
+/// Matches the part of a sync bookmark before its job name.
+fn sync_bookmark() -> &'static regex::Regex {
+    SYNC_BOOKMARK.get_or_init(|| {
+        regex::Regex::new(&format!(
+            r"^{}#{}(?:-{})?-sync-",
+            DATASET_PATTERN,
+            name_pattern(),
+            LABEL_PATTERN
+        ))
+        .unwrap()
+    })
 }
 
 fn is_normal_snapshot(path: &str) -> bool {
-    let re = regex::Regex::new(r"^[a-z/]+@\d{4}-\d{2}-\d{2}T\d{4}$").unwrap();
-    re.is_match(path)
+    NORMAL_SNAPSHOT
+        .get_or_init(|| {
+            regex::Regex::new(&format!(
+                r"^{}@{}(?:-{})?$",
+                DATASET_PATTERN,
+                name_pattern(),
+                LABEL_PATTERN
+            ))
+            .unwrap()
+        })
+        .is_match(path)
 }
 
 impl Remote {
-    fn cmd(&self, args: &[&str]) -> Command {
-        let mut cmd = match &self.transport {
-            Transport::Local => std::process::Command::new("zfs"),
-            Transport::SSH(host) => {
-                let mut tmp = std::process::Command::new("ssh");
-                tmp.args(&["-o", "ConnectTimeout=20", host]);
-                tmp.arg("zfs");
+    /// Another dataset reached the same way as this one.
+    fn with_dataset(&self, dataset: &str) -> Remote {
+        Remote {
+            dataset: dataset.to_string(),
+            transport: self.transport.clone(),
+        }
+    }
+
+    /// The remote's host for messages, e.g. `rpi4.local` or `this host`.
+    fn host(&self) -> &str {
+        match &self.transport {
+            Transport::Local => "this host",
+            Transport::SSH(host) => &host.target,
+        }
+    }
+
+    /// Runs `program` on the remote's host.
+    fn command(&self, program: &str, args: &[&str]) -> Command {
+        match &self.transport {
+            Transport::Local => {
+                let mut tmp = std::process::Command::new(program);
+                tmp.args(args);
                 tmp
             }
-        };
-        cmd.args(args);
-        cmd
+            Transport::SSH(host) => {
+                let settings = SSH_SETTINGS.get_or_init(SshSettings::default);
+                if let Some((rsh, rsh_args)) = settings.rsh.split_first() {
+                    // like rsync, a custom remote shell only gets the host
+                    // and the command, since it may not understand ssh options
+                    if host.port.is_some() {
+                        panic!("cannot pass a port to --rsh, include it in the --rsh command");
+                    }
+                    let mut tmp = std::process::Command::new(rsh);
+                    tmp.args(rsh_args);
+                    tmp.args([&host.target, &remote_command(program, args)]);
+                    tmp
+                } else {
+                    start_master(host, settings);
+                    let mut tmp = ssh(host, settings);
+                    tmp.arg(&host.target);
+                    tmp.arg(remote_command(program, args));
+                    tmp
+                }
+            }
+        }
+    }
+
+    fn cmd(&self, args: &[&str]) -> Command {
+        self.command("zfs", args)
     }
 
     fn exec(&self, args: &[&str]) -> Result<String, String> {
-        let out = self.cmd(args).output().unwrap();
+        trace(&[&self.cmd(args)]);
+        #[cfg(feature = "replay")]
+        let out = match replay::replayed(&self.cmd(args)) {
+            Some(out) => out,
+            None => self.output(args)?,
+        };
+        #[cfg(not(feature = "replay"))]
+        let out = self.output(args)?;
+        #[cfg(feature = "replay")]
+        replay::record(&self.cmd(args), out.status, &out.stdout, &out.stderr);
+
         if out.status.success() {
-            Ok(String::from_utf8(out.stdout).unwrap())
+            // a name that isn't UTF-8 comes out with U+FFFD in it, so it
+            // matches no snapshot zbak would prune
+            Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+        } else if let Some(denied) =
+            delegate::PermissionDenied::detect(self, args, &String::from_utf8_lossy(&out.stderr))
+        {
+            Err(denied.to_string())
         } else {
-            Err(String::from_utf8(out.stderr).unwrap())
+            Err(CommandError::new(&self.cmd(args), out.status, &out.stderr).to_string())
         }
     }
 
-    fn internal_list_snapshots(&self) -> Vec<Snapshot> {
-        let out = match self.exec(&[
-            "list",
-            "-t",
-            "snapshot",
-            "-o",
-            "name,creation",
-            "-Hp",
-            &self.dataset,
-        ]) {
+    fn output(&self, args: &[&str]) -> Result<Output, String> {
+        match COMMAND_TIMEOUT.load(Ordering::Relaxed) {
+            0 => Ok(self.cmd(args).output().unwrap()),
+            secs => output_with_timeout(self.cmd(args), std::time::Duration::from_secs(secs))
+                .ok_or_else(|| format!("timed out after {}s: zfs {}", secs, args.join(" "))),
+        }
+    }
+
+    /// Lists every snapshot or bookmark (`kind`) of `self.dataset` itself,
+    /// including ones not created by zbak, oldest first.
+    fn internal_list(&self, kind: &str) -> Vec<Snapshot> {
+        let rows = if platform::zfs_json(self) {
+            self.list_json(kind)
+        } else {
+            None
+        };
+        let rows = rows.unwrap_or_else(|| self.list_tabs(kind));
+
+        rows.into_iter()
+            .map(|(path, creation, guid)| {
+                // creation is `-` where zfs can't tell, fall back to the name
+                let time = match creation.parse::<i64>() {
+                    Ok(x) => chrono::Utc.timestamp(x, 0),
+                    Err(_) => time_from_name(&path)
+                        .unwrap_or_else(|| panic!("cannot tell when {} was created", path)),
+                };
+                Snapshot { path, time, guid }
+            })
+            .collect()
+    }
+
+    /// Runs `zfs list` for `internal_list`, where a dataset that doesn't
+    /// exist has nothing in it.
+    fn list(&self, args: &[&str]) -> String {
+        match self.exec(args) {
             Ok(x) => x,
             Err(e) => {
                 if e.contains("does not exist") {
@@ -109,64 +988,155 @@ impl Remote {
                     panic!("cmd err: {}", e);
                 }
             }
-        };
+        }
+    }
 
+    /// (name, creation, guid) of each snapshot or bookmark, from the
+    /// tab-separated output every zfs has.
+    fn list_tabs(&self, kind: &str) -> Vec<(String, String, String)> {
+        // zfs sorts by creation order itself, -d 1 leaves out children
+        let out = self.list(&[
+            "list",
+            "-t",
+            kind,
+            "-d",
+            "1",
+            "-s",
+            "createtxg",
+            "-o",
+            "name,creation,guid",
+            "-Hp",
+            &self.dataset,
+        ]);
         out.lines()
             .map(|line| {
-                let parts = line.split('\t').collect::<Vec<_>>();
-                Snapshot {
-                    path: parts[0].to_string(),
-                    time: chrono::Utc.timestamp(parts[1].parse::<i64>().unwrap(), 0),
-                }
+                let mut parts = line.split('\t');
+                let mut next = || parts.next().unwrap().to_string();
+                (next(), next(), next())
             })
             .collect()
     }
 
+    /// Like `list_tabs`, from `zfs list -j` on OpenZFS 2.3 and newer, which
+    /// no odd character in a name can confuse. None if the output can't be
+    /// read, so that the tabs are tried instead.
+    fn list_json(&self, kind: &str) -> Option<Vec<(String, String, String)>> {
+        let out = self.list(&[
+            "list",
+            "-j",
+            "-p",
+            "-t",
+            kind,
+            "-d",
+            "1",
+            "-o",
+            "name,creation,guid",
+            &self.dataset,
+        ]);
+        if out.is_empty() {
+            return Some(Vec::new());
+        }
+        let value = json::parse(&out).ok()?;
+        let mut rows = Vec::new();
+        for (name, dataset) in value.get("datasets")?.members() {
+            let property = |x: &str| dataset.get("properties")?.get(x)?.get("value")?.text();
+            let createtxg = dataset.get("createtxg")?.text()?.parse::<u64>().ok()?;
+            rows.push((
+                createtxg,
+                (
+                    name.clone(),
+                    property("creation")?.to_string(),
+                    property("guid")?.to_string(),
+                ),
+            ));
+        }
+        // objects have no order, so sort like -s createtxg
+        rows.sort_by_key(|x| x.0);
+        Some(rows.into_iter().map(|x| x.1).collect())
+    }
+
     fn list_snapshots(&self) -> Vec<Snapshot> {
-        self.internal_list_snapshots()
+        self.internal_list("snapshot")
             .into_iter()
             .filter(|snap| is_normal_snapshot(&snap.path))
             .collect()
     }
 
     fn list_bookmarks(&self, name: &str) -> Vec<Snapshot> {
+        let re = sync_bookmark();
+        let suffix = format!("-sync-{}", name);
+
+        self.internal_list("bookmark")
+            .into_iter()
+            .filter(|snap| re.is_match(&snap.path) && snap.path.ends_with(&suffix))
+            .collect()
+    }
+
+    /// Every job's newest sync bookmark, by job name. Their snapshots are
+    /// the bases of the next incremental sends, e.g. for `send --changes`.
+    fn latest_sync_bookmarks(&self) -> std::collections::HashMap<String, Snapshot> {
+        let re = sync_bookmark();
+        let mut latest = std::collections::HashMap::new();
+        // listed oldest first, so newer bookmarks replace older ones
+        for bookmark in self.internal_list("bookmark") {
+            if let Some(m) = re.find(&bookmark.path) {
+                latest.insert(bookmark.path[m.end()..].to_string(), bookmark);
+            }
+        }
+        latest
+    }
+
+    /// Clones depending on each of the dataset's snapshots, for the
+    /// snapshots that have any; zfs refuses to destroy those.
+    fn dependent_clones(&self) -> std::collections::HashMap<String, String> {
         let out = match self.exec(&[
-            "list",
-            "-t",
-            "bookmark",
+            "get",
+            "-H",
             "-o",
-            "name,creation",
-            "-Hp",
+            "name,value",
+            "-d",
+            "1",
+            "-t",
+            "snapshot",
+            "clones",
             &self.dataset,
         ]) {
             Ok(x) => x,
-            Err(e) => {
-                if e.contains("does not exist") {
-                    "".to_string()
-                } else {
-                    panic!("cmd err: {}", e);
-                }
-            }
+            Err(e) if e.contains("does not exist") => String::new(),
+            Err(e) => panic!("cmd err: {}", e),
         };
-
-        let re = regex::Regex::new(r"^[a-z/]+#\d{4}-\d{2}-\d{2}T\d{4}-sync-").unwrap();
-
         out.lines()
-            .map(|line| {
-                let parts = line.split('\t').collect::<Vec<_>>();
-                Snapshot {
-                    path: parts[0].to_string(),
-                    time: chrono::Utc.timestamp(parts[1].parse::<i64>().unwrap(), 0),
-                }
-            })
-            .filter(|snap| {
-                re.is_match(&snap.path) && snap.path.ends_with(&("-sync-".to_string() + name))
-            })
+            .filter_map(|line| line.split_once('\t'))
+            .filter(|(_, clones)| !clones.is_empty() && *clones != "-")
+            .map(|(path, clones)| (path.to_string(), clones.to_string()))
             .collect()
     }
 
+    fn exists(&self, dataset: &str) -> bool {
+        match self.exec(&["list", "-H", "-o", "name", dataset]) {
+            Ok(_) => true,
+            Err(e) if e.contains("does not exist") => false,
+            Err(e) => panic!("cmd err: {}", e),
+        }
+    }
+
+    /// Whether `dataset` is a zvol rather than a filesystem.
+    fn is_volume(&self, dataset: &str) -> bool {
+        self.exec(&["get", "-H", "-o", "value", "type", dataset])
+            .unwrap()
+            .trim()
+            == "volume"
+    }
+
     fn snapshot(&self, path: &str) {
-        self.exec(&["snapshot", path]).unwrap();
+        self.snapshots(&[path]).unwrap();
+    }
+
+    /// Creates all of `paths` atomically, they must be in the same pool.
+    fn snapshots(&self, paths: &[&str]) -> Result<(), String> {
+        let mut args = vec!["snapshot"];
+        args.extend_from_slice(paths);
+        self.exec(&args).map(|_| ())
     }
 
     fn bookmark(&self, base: &str, mark: &str) {
@@ -177,9 +1147,73 @@ impl Remote {
         if !path.contains('@') {
             panic!("invalid path for snapshot");
         }
+        if is_protected(path) {
+            panic!("refusing to destroy protected snapshot {}", path);
+        }
         self.exec(&["destroy", path]).unwrap();
     }
 
+    /// (snapshot, tag) of every hold on `paths`.
+    fn holds(&self, paths: &[&str]) -> Vec<(String, String)> {
+        // a few hundred at a time, thousands of snapshots would overflow
+        // the command line
+        let mut out = Vec::new();
+        for chunk in paths.chunks(256) {
+            let mut args = vec!["holds", "-H"];
+            args.extend(chunk);
+            for line in self.exec(&args).unwrap().lines() {
+                let mut fields = line.split('\t');
+                let path = fields.next().unwrap().to_string();
+                out.push((path, fields.next().unwrap().to_string()));
+            }
+        }
+        out
+    }
+
+    fn release(&self, tag: &str, path: &str) {
+        self.exec(&["release", tag, path]).unwrap();
+    }
+
+    /// Destroys a snapshot for retention purposes. Snapshots held by
+    /// someone else are skipped with a warning; holds tagged `release_tag`
+    /// are considered stale and released first.
+    fn prune_snapshot(&self, path: &str, release_tag: Option<&str>) {
+        if is_protected(path) {
+            panic!("refusing to destroy protected snapshot {}", path);
+        }
+        let err = match self.exec(&["destroy", path]) {
+            Ok(_) => return,
+            Err(e) => e,
+        };
+
+        let mut holds = self
+            .holds(&[path])
+            .into_iter()
+            .map(|x| x.1)
+            .collect::<Vec<_>>();
+        if holds.is_empty() {
+            panic!("cmd err: {}", err);
+        }
+
+        if let Some(tag) = release_tag {
+            if holds.iter().any(|x| x == tag) {
+                println!("Releasing hold {} on {}.", tag, path);
+                self.release(tag, path);
+                holds.retain(|x| x != tag);
+            }
+        }
+
+        if holds.is_empty() {
+            self.destroy_snapshot(path);
+        } else {
+            println!(
+                "Warning: not removing {}, held by {}.",
+                path,
+                holds.join(", ")
+            );
+        }
+    }
+
     fn destroy_bookmark(&self, path: &str) {
         if !path.contains('#') {
             panic!("invalid path for bookmark");
@@ -195,6 +1229,16 @@ struct Spec {
     daily: u64,
     hourly: u64,
     frequently: u64,
+    /// How many labeled snapshots to keep per label, or all of them
+    labeled: Option<u64>,
+    /// Policies of their own for some labels, e.g. `pre-upgrade=12m`
+    labels: Vec<(String, Spec)>,
+}
+
+impl Spec {
+    fn has_slots(&self) -> bool {
+        self.monthly + self.weekly + self.daily + self.hourly + self.frequently > 0
+    }
 }
 
 struct PruningPlan {
@@ -202,31 +1246,52 @@ struct PruningPlan {
     remove: Vec<Snapshot>,
 }
 
-fn find_prunable(
-    now: &chrono::DateTime<Utc>,
-    spec: &Spec,
-    mut snapshots: Vec<Snapshot>,
-) -> PruningPlan {
-    snapshots.sort_by(|a, b| a.time.cmp(&b.time));
+impl PruningPlan {
+    /// Takes the snapshots with one of `guids` out of `remove`, e.g. the
+    /// base of the next incremental send, and returns them.
+    fn protect(&mut self, guids: &[String]) -> Vec<Snapshot> {
+        let (protected, remove) = std::mem::take(&mut self.remove)
+            .into_iter()
+            .partition(|x| guids.contains(&x.guid));
+        self.remove = remove;
+        protected
+    }
+}
 
-    let mut wanted = Vec::new();
+/// A period that retention wants one snapshot for, starting at `start`.
+struct Slot {
+    kind: &'static str,
+    start: chrono::DateTime<Utc>,
+    end: chrono::DateTime<Utc>,
+}
 
-    let mut cursor_month = now
-        .with_day(1)
+/// The slots `spec` asks to fill, counting back from `now`.
+fn slots(now: &chrono::DateTime<Utc>, spec: &Spec) -> Vec<Slot> {
+    let mut out = Vec::new();
+    let mut add = |kind, start, end| out.push(Slot { kind, start, end });
+
+    let previous_month = |x: chrono::DateTime<Utc>| {
+        if x.month() == 1 {
+            x.with_year(x.year() - 1).unwrap().with_month(12).unwrap()
+        } else {
+            x.with_month(x.month() - 1).unwrap()
+        }
+    };
+    let next_month = |x: chrono::DateTime<Utc>| {
+        if x.month() == 12 {
+            x.with_year(x.year() + 1).unwrap().with_month(1).unwrap()
+        } else {
+            x.with_month(x.month() + 1).unwrap()
+        }
+    };
+    let mut cursor_month = now
+        .with_day(1)
         .unwrap()
         .duration_round(Duration::days(1))
         .unwrap();
     for _ in 0..spec.monthly {
-        wanted.push(cursor_month);
-        cursor_month = if cursor_month.month() == 1 {
-            cursor_month
-                .with_year(cursor_month.year() - 1)
-                .unwrap()
-                .with_month(12)
-                .unwrap()
-        } else {
-            cursor_month.with_month(cursor_month.month() - 1).unwrap()
-        };
+        add("monthly", cursor_month, next_month(cursor_month));
+        cursor_month = previous_month(cursor_month);
     }
 
     let mut cursor_week = now
@@ -234,83 +1299,762 @@ fn find_prunable(
         .duration_round(Duration::days(1))
         .unwrap();
     for _ in 0..spec.weekly {
-        wanted.push(cursor_week);
+        add("weekly", cursor_week, cursor_week + Duration::days(7));
         cursor_week = cursor_week.sub(Duration::days(7));
     }
 
-    let mut cursor_day = now.duration_round(Duration::days(1)).unwrap();
-    for _ in 0..spec.daily {
-        wanted.push(cursor_day);
-        cursor_day = cursor_day.sub(Duration::days(1));
+    let periods = [
+        ("daily", spec.daily, Duration::days(1)),
+        ("hourly", spec.hourly, Duration::hours(1)),
+        ("frequent", spec.frequently, Duration::minutes(15)),
+    ];
+    for (kind, count, period) in periods {
+        let mut cursor = now.duration_round(period).unwrap();
+        for _ in 0..count {
+            add(kind, cursor, cursor + period);
+            cursor = cursor.sub(period);
+        }
     }
 
-    let mut cursor_hour = now.duration_round(Duration::hours(1)).unwrap();
-    for _ in 0..spec.hourly {
-        wanted.push(cursor_hour);
-        cursor_hour = cursor_hour.sub(Duration::hours(1));
+    out
+}
+
+/// For `times`, oldest first, whether each fills a slot of `spec`, or None
+/// for those newer than every slot.
+fn fill_slots(
+    now: &chrono::DateTime<Utc>,
+    spec: &Spec,
+    times: &[chrono::DateTime<Utc>],
+) -> Vec<Option<bool>> {
+    let mut wanted = slots(now, spec)
+        .into_iter()
+        .map(|x| x.start)
+        .collect::<Vec<_>>();
+    wanted.sort_by(|a, b| b.cmp(a));
+
+    let mut out = vec![None; times.len()];
+    for (i, time) in times.iter().enumerate() {
+        let mut keep = false;
+        if wanted.is_empty() {
+            break;
+        }
+        while !wanted.is_empty() && time > wanted.last().unwrap() {
+            wanted.pop().unwrap();
+            keep = true;
+        }
+        out[i] = Some(keep);
     }
+    out
+}
 
-    let mut cursor_frequent = now.duration_round(Duration::minutes(15)).unwrap();
-    for _ in 0..spec.frequently {
-        wanted.push(cursor_frequent);
-        cursor_frequent = cursor_frequent.sub(Duration::minutes(15));
+/// Splits `snapshots`, oldest first as listed by zfs, into those filling a
+/// slot of `spec` and those to remove. Snapshots newer than every slot are
+/// in neither. Labeled snapshots are kept by the policy `spec` has for
+/// their label, or else the newest of each label are.
+fn find_prunable(
+    now: &chrono::DateTime<Utc>,
+    spec: &Spec,
+    snapshots: impl IntoIterator<Item = Snapshot>,
+) -> PruningPlan {
+    let snapshots = snapshots.into_iter().collect::<Vec<_>>();
+    let mut classes: Vec<(Option<&str>, Vec<usize>)> = Vec::new();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        let label = snapshot_label(&snapshot.path);
+        match classes.iter_mut().find(|x| x.0 == label) {
+            Some(class) => class.1.push(i),
+            None => classes.push((label, vec![i])),
+        }
     }
 
-    wanted.sort_by(|a, b| b.cmp(a));
+    // whether each snapshot is kept, in the order they were listed
+    let mut keep = vec![None; snapshots.len()];
+    for (label, members) in classes {
+        let times = members
+            .iter()
+            .map(|&i| snapshots[i].time)
+            .collect::<Vec<_>>();
+        let (decisions, newest) = match label {
+            None => (fill_slots(now, spec, &times), 0),
+            Some(label) => match spec.labels.iter().find(|x| x.0 == label) {
+                Some((_, own)) if !own.has_slots() => {
+                    (vec![Some(false); times.len()], own.labeled.unwrap_or(0))
+                }
+                Some((_, own)) => (fill_slots(now, own, &times), own.labeled.unwrap_or(0)),
+                None => match spec.labeled {
+                    Some(count) => (vec![Some(false); times.len()], count),
+                    None => (vec![Some(true); times.len()], 0),
+                },
+            },
+        };
+        for (n, (&i, decision)) in members.iter().zip(decisions).enumerate() {
+            let is_newest = members.len() - n <= newest as usize;
+            keep[i] = if is_newest { Some(true) } else { decision };
+        }
+    }
 
     let mut out = PruningPlan {
         keep: vec![],
         remove: vec![],
     };
+    for (snapshot, keep) in snapshots.into_iter().zip(keep) {
+        match keep {
+            Some(true) => out.keep.push(snapshot),
+            Some(false) => out.remove.push(snapshot),
+            None => {}
+        }
+    }
+    out
+}
 
-    for snapshot in snapshots {
-        let mut keep = false;
-        if wanted.is_empty() {
-            break;
+/// Size of the stream `zfs send` would produce, from a dry run.
+fn estimate_size(origin: &Remote, send_args: &[&str]) -> Option<u64> {
+    let mut args = vec!["send", "-nP"];
+    args.extend(&send_args[1..]);
+    let out = origin.exec(&args).ok()?;
+    out.lines()
+        .rev()
+        .find_map(|line| line.strip_prefix("size\t"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Settings shared by every transfer of a send run.
+struct TransferOptions {
+    bwlimit: Option<throttle::Schedule>,
+    deadline: Option<Instant>,
+    /// Send unencrypted and encrypt on receive, instead of a raw send
+    encryption: Option<encryption::Encryption>,
+    /// From `--nice` and `--ionice`
+    nice: Option<i32>,
+    ionice: Option<String>,
+    /// From `--send-opt` and `--recv-opt`, split into words
+    send_opts: Vec<String>,
+    recv_opts: Vec<String>,
+}
+
+/// Splits `--send-opt '-o a=b'` style values into separate arguments.
+fn split_opts(opts: &[String]) -> Vec<String> {
+    opts.iter()
+        .flat_map(|x| x.split_whitespace())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// `args` with `extra` inserted after the subcommand, so that they still
+/// come before the snapshot or dataset operands.
+fn with_opts<'a>(args: &[&'a str], extra: &'a [String]) -> Vec<&'a str> {
+    let mut out = vec![args[0]];
+    out.extend(extra.iter().map(|x| x.as_str()));
+    out.extend(&args[1..]);
+    out
+}
+
+impl TransferOptions {
+    fn zfs(&self, remote: &Remote, args: &[&str]) -> Command {
+        let priority = priority(self.nice, self.ionice.as_deref(), platform::of(remote));
+        match priority.split_first() {
+            None => remote.cmd(args),
+            Some((program, rest)) => {
+                let mut all = rest.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+                all.push("zfs");
+                all.extend(args);
+                remote.command(program, &all)
+            }
         }
-        while !wanted.is_empty() && &snapshot.time > wanted.last().unwrap() {
-            wanted.pop().unwrap();
-            keep = true;
+    }
+}
+
+/// A command that exited unsuccessfully, with what it printed to stderr.
+struct CommandError {
+    command: String,
+    status: std::process::ExitStatus,
+    stderr: String,
+}
+
+impl CommandError {
+    fn new(cmd: &Command, status: std::process::ExitStatus, stderr: &[u8]) -> CommandError {
+        CommandError {
+            command: command_line(cmd),
+            status,
+            stderr: String::from_utf8_lossy(stderr).trim().to_string(),
         }
-        if keep {
-            out.keep.push(snapshot);
-        } else {
-            out.remove.push(snapshot);
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "`{}` failed ({})", self.command, self.status)?;
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr)?;
         }
+        Ok(())
     }
+}
 
-    out
+enum TransferError {
+    Failed(CommandError),
+    PermissionDenied(delegate::PermissionDenied),
+    DeadlineExceeded,
+    Interrupted,
 }
 
-fn send_nonincremental(origin: &Remote, destination: &Remote, name: &str) {
-    let mut snapshots = origin.list_snapshots();
-    snapshots.sort_by(|a, b| a.time.cmp(&b.time));
-    let path = &snapshots.last().unwrap().path;
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransferError::Failed(err) => write!(f, "{}", err),
+            TransferError::PermissionDenied(err) => write!(f, "{}", err),
+            TransferError::DeadlineExceeded => write!(f, "transfer deadline exceeded"),
+            TransferError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
 
-    println!("Sending...");
+/// Why `zfs args`, run on `remote` as `cmd`, failed as part of a transfer.
+fn failed(
+    cmd: &Command,
+    remote: &Remote,
+    args: &[&str],
+    status: std::process::ExitStatus,
+    stderr: &[u8],
+) -> TransferError {
+    match delegate::PermissionDenied::detect(remote, args, &String::from_utf8_lossy(stderr)) {
+        Some(denied) => TransferError::PermissionDenied(denied),
+        None => TransferError::Failed(CommandError::new(cmd, status, stderr)),
+    }
+}
+
+/// Runs `cmd` like `Command::output`, through the replay fixture if any.
+fn command_output(cmd: &mut Command) -> std::io::Result<Output> {
+    #[cfg(feature = "replay")]
+    if let Some(out) = replay::replayed(cmd) {
+        return Ok(out);
+    }
+    let out = cmd.output()?;
+    #[cfg(feature = "replay")]
+    replay::record(cmd, out.status, &out.stdout, &out.stderr);
+    Ok(out)
+}
+
+/// Reads a child's stderr in the background, so that it can be reported if
+/// the child fails without the child blocking on a full pipe.
+fn collect_stderr(child: &mut std::process::Child) -> std::thread::JoinHandle<Vec<u8>> {
+    let mut pipe = child.stderr.take().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf).ok();
+        buf
+    })
+}
 
-    let mut producer = origin
-        .cmd(&["send", "-w", path])
+/// Like `collect_stderr`, but also prints each line as it arrives, tagged
+/// e.g. `[recv]`, so that warnings of a long transfer show up while it runs.
+fn stream_stderr(
+    child: &mut std::process::Child,
+    tag: &'static str,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    let mut pipe = std::io::BufReader::new(child.stderr.take().unwrap());
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        loop {
+            let start = buf.len();
+            match pipe.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf[start..]);
+                    println!("[{}] {}", tag, line.trim_end());
+                }
+            }
+        }
+        buf
+    })
+}
+
+/// Words to prefix a command with to run it at a lower CPU or I/O priority
+/// on `platform`, where ionice only exists on Linux.
+fn priority(nice: Option<i32>, ionice: Option<&str>, platform: platform::Platform) -> Vec<String> {
+    let mut out = Vec::new();
+    if let Some(nice) = nice {
+        out.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+    }
+    // send warns that other hosts go without
+    if let Some(class) = ionice.filter(|_| platform == platform::Platform::Linux) {
+        out.extend(["ionice".to_string(), "-c".to_string(), class.to_string()]);
+    }
+    out
+}
+
+/// Pipes `zfs send` on the origin into `zfs recv` on the destination. The
+/// stream is relayed through zbak to track progress and apply any bandwidth
+/// limit.
+fn transfer(
+    origin: &Remote,
+    send_args: &[&str],
+    destination: &Remote,
+    recv_args: &[&str],
+    options: &TransferOptions,
+    progress: &mut progress::Progress,
+) -> Result<u64, TransferError> {
+    let send_args = with_opts(send_args, &options.send_opts);
+    let recv_args = with_opts(recv_args, &options.recv_opts);
+    progress.start_transfer(send_args.last().unwrap(), estimate_size(origin, &send_args));
+
+    let mut producer_cmd = options.zfs(origin, &send_args);
+    let mut consumer_cmd = options.zfs(destination, &recv_args);
+    // zbak itself sits in the middle of the pipe
+    trace(&[&producer_cmd, &consumer_cmd]);
+    #[cfg(feature = "replay")]
+    if let Some(result) = replay::replayed_transfer(&producer_cmd, &consumer_cmd) {
+        if result.is_ok() {
+            progress.finish_transfer();
+        }
+        return result;
+    }
+    let mut producer = producer_cmd
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .unwrap();
-
-    let consumer = destination
-        .cmd(&["recv", "-uF", &destination.dataset])
-        .stdin(producer.stdout.take().unwrap())
+    let producer_stderr = stream_stderr(&mut producer, "send");
+    let mut consumer = consumer_cmd
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .unwrap();
+    let consumer_stderr = stream_stderr(&mut consumer, "recv");
+
+    let mut stream = producer.stdout.take().unwrap();
+    let mut sink = consumer.stdin.take().unwrap();
+    // a failed write means recv exited, which its status reports below
+    let copied = throttle::copy(
+        &mut stream,
+        &mut sink,
+        options.bwlimit.as_ref(),
+        options.deadline,
+        |n| progress.add_bytes(n),
+    );
+    drop(stream);
+    drop(sink);
+
+    let bytes = *copied.as_ref().unwrap_or(&0);
+    summary::add_bytes(bytes);
+    let aborted = match copied {
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Some(TransferError::Interrupted),
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Some(TransferError::DeadlineExceeded),
+        _ => None,
+    };
+    if let Some(err) = aborted {
+        // without a resume token the partial receive is discarded by zfs
+        producer.kill().ok();
+        consumer.kill().ok();
+        producer.wait().unwrap();
+        consumer.wait().unwrap();
+        return Err(err);
+    }
+
+    let status = consumer.wait().unwrap();
+    let stderr = consumer_stderr.join().unwrap();
+    #[cfg(feature = "replay")]
+    replay::record(&consumer_cmd, status, &[], &stderr);
+    if !status.success() {
+        producer.kill().ok();
+        producer.wait().unwrap();
+        return Err(failed(
+            &consumer_cmd,
+            destination,
+            &recv_args,
+            status,
+            &stderr,
+        ));
+    }
+
+    let status = producer.wait().unwrap();
+    let stderr = producer_stderr.join().unwrap();
+    #[cfg(feature = "replay")]
+    replay::record(&producer_cmd, status, &[], &stderr);
+    if !status.success() {
+        return Err(failed(&producer_cmd, origin, &send_args, status, &stderr));
+    }
+
+    progress.finish_transfer();
+    Ok(bytes)
+}
+
+/// User property on a replica holding the GUID of the dataset it was
+/// replicated from.
+const SOURCE_PROPERTY: &str = "zbak:source-guid";
+
+fn dataset_guid(remote: &Remote) -> String {
+    remote
+        .exec(&["get", "-H", "-o", "value", "guid", &remote.dataset])
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+/// Fails if the destination was last replicated from another dataset than
+/// the origin now is, i.e. the origin was destroyed and recreated or
+/// replaced under the same name.
+fn check_source(origin: &Remote, destination: &Remote) -> Result<(), String> {
+    let recorded = match destination.exec(&[
+        "get",
+        "-H",
+        "-o",
+        "value",
+        SOURCE_PROPERTY,
+        &destination.dataset,
+    ]) {
+        Ok(x) => x.trim().to_string(),
+        Err(e) if e.contains("does not exist") => return Ok(()),
+        Err(e) => panic!("cmd err: {}", e),
+    };
+    // replicated by an older zbak, or by hand
+    if recorded == "-" {
+        return Ok(());
+    }
+    let guid = dataset_guid(origin);
+    if recorded != guid {
+        return Err(format!(
+            "{} was replicated from a dataset with guid {}, but {} has guid {}; it was recreated or replaced",
+            destination.dataset, recorded, origin.dataset, guid
+        ));
+    }
+    Ok(())
+}
 
-    consumer.wait_with_output().unwrap();
+/// Fails if the destination was changed since its newest snapshot, which
+/// zbak never does, e.g. because the replica was used during an outage.
+/// Receiving would discard those changes.
+fn check_unchanged(destination: &Remote) -> Result<(), String> {
+    let newest = match destination.internal_list("snapshot").pop() {
+        Some(x) => x,
+        // nothing was received yet, or it doesn't exist
+        None => return Ok(()),
+    };
+    let written = destination
+        .exec(&["get", "-Hp", "-o", "value", "written", &destination.dataset])
+        .unwrap()
+        .trim()
+        .parse::<u64>()
+        .unwrap_or(0);
+    if written > 0 {
+        return Err(format!(
+            "{} was written to since {} ({}), was it used after a failover?",
+            destination.dataset,
+            newest.path,
+            format_bytes(written)
+        ));
+    }
+    Ok(())
+}
 
-    let bookmark = path.replace('@', "#") + &format!("-sync-{}", name);
+/// The source's record of where the job `name` replicates to, so that a
+/// `--name` reused for a second destination is caught before the two
+/// destroy each other's bookmarks.
+fn destination_property(name: &str) -> String {
+    // user property names only take lowercase letters, digits and `:-._`,
+    // so anything else in a job name, e.g. RPi4, is written as :xx bytes
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => encoded.push(byte as char),
+            _ => encoded += &format!(":{:02x}", byte),
+        }
+    }
+    format!("zbak:destination:{}", encoded)
+}
+
+/// The GUID of the dataset the job `name` last replicated the origin to, if
+/// it recorded one.
+fn recorded_destination(origin: &Remote, name: &str) -> Option<String> {
+    // only set locally, children inherit it but have destinations of their own
+    let recorded = origin
+        .exec(&[
+            "get",
+            "-H",
+            "-s",
+            "local",
+            "-o",
+            "value",
+            &destination_property(name),
+            &origin.dataset,
+        ])
+        .unwrap();
+    Some(recorded.trim().to_string()).filter(|x| !x.is_empty())
+}
+
+/// Fails if the job `name` last replicated the origin to another dataset
+/// than the destination.
+fn check_destination(origin: &Remote, destination: &Remote, name: &str) -> Result<(), String> {
+    let property = destination_property(name);
+    let recorded = match recorded_destination(origin, name) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let guid = if destination.exists(&destination.dataset) {
+        dataset_guid(destination)
+    } else {
+        "none".to_string()
+    };
+    if recorded != guid {
+        return Err(format!(
+            "job {} replicates {} to a dataset with guid {}, but {} has guid {}; give each destination its own --name, or if the destination was replaced, run zfs inherit {} {}",
+            name, origin.dataset, recorded, destination.dataset, guid, property, origin.dataset
+        ));
+    }
+    Ok(())
+}
+
+fn record_source(origin: &Remote, destination: &Remote, name: &str) {
+    let property = format!("{}={}", SOURCE_PROPERTY, dataset_guid(origin));
+    destination
+        .exec(&["set", &property, &destination.dataset])
+        .unwrap();
+    let property = format!(
+        "{}={}",
+        destination_property(name),
+        dataset_guid(destination)
+    );
+    origin.exec(&["set", &property, &origin.dataset]).unwrap();
+}
+
+/// Checks that the destination now holds `snapshot` with the source's GUID,
+/// so that a failed or mangled receive never becomes the base of the next
+/// incremental send.
+fn verify_received(origin: &Remote, destination: &Remote, snapshot: &str) -> Result<(), String> {
+    let (_, name) = snapshot.split_once('@').unwrap();
+    let received = format!("{}@{}", destination.dataset, name);
+    let guid = |remote: &Remote, path: &str| {
+        remote
+            .exec(&["get", "-H", "-o", "value", "guid", path])
+            .map(|x| x.trim().to_string())
+    };
+    let source_guid = guid(origin, snapshot)?;
+    let received_guid = match guid(destination, &received) {
+        Ok(x) => x,
+        Err(_) => return Err(format!("{} was not received", received)),
+    };
+    if source_guid != received_guid {
+        return Err(format!(
+            "{} has guid {}, but {} has guid {}",
+            received, received_guid, snapshot, source_guid
+        ));
+    }
+    Ok(())
+}
+
+fn send_nonincremental(
+    origin: &Remote,
+    destination: &Remote,
+    cmd: &SendCommand,
+    job: &str,
+    options: &TransferOptions,
+    progress: &mut progress::Progress,
+) {
+    let snapshots = origin.list_snapshots();
+    let path = match snapshots.last() {
+        Some(x) => &x.path,
+        None => {
+            send_error(format!(
+                "{} has no snapshots taken by zbak snap to send.",
+                origin.dataset
+            ));
+            return;
+        }
+    };
+
+    // a typo in --to would otherwise start a full send to a new dataset
+    let create = !destination.exists(&destination.dataset);
+    if create && !cmd.create_dest {
+        send_error(format!(
+            "{} does not exist, pass --create-dest to create it with a full send.",
+            destination.dataset
+        ));
+        return;
+    }
+
+    // recv -F destroys whatever the destination already has
+    let existing = destination.internal_list("snapshot");
+    if let Some(snapshot) = existing.iter().find(|x| is_protected(&x.path)) {
+        send_error(format!(
+            "a full send would destroy destination's protected {}.",
+            snapshot.path
+        ));
+        return;
+    }
+    let clones = destination.dependent_clones();
+    if let Some((snapshot, clones)) = clones.iter().next() {
+        send_error(format!("a full send would destroy destination's {}, which clone(s) {} depend on. Destroy them first, e.g. with zbak clone --cleanup.",
+            snapshot, clones
+        ));
+        return;
+    }
+
+    if defer_for_scan(
+        origin,
+        destination,
+        cmd.defer_during_scan.as_deref(),
+        &[vec!["send", path]],
+    ) {
+        return;
+    }
+
+    println!("Sending...");
+
+    let mut send_args = vec!["send", "-w", path];
+    let mut recv_args = vec!["recv".to_string(), "-uF".to_string()];
+    if let Some(encryption) = &options.encryption {
+        send_args.remove(1);
+        recv_args.extend(encryption.recv_options());
+    }
+    if create {
+        for property in &cmd.dest_properties {
+            recv_args.push("-o".to_string());
+            recv_args.push(property.clone());
+        }
+    }
+    recv_args.push(destination.dataset.clone());
+
+    let started = Instant::now();
+    match transfer(
+        origin,
+        &send_args,
+        destination,
+        &recv_args.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+        options,
+        progress,
+    ) {
+        Ok(bytes) => history::record(job, "full", bytes, started.elapsed()),
+        Err(err) => {
+            if !matches!(err, TransferError::Interrupted) {
+                send_error(err.to_string());
+            }
+            return;
+        }
+    }
+
+    if let Err(err) = verify_received(origin, destination, path) {
+        send_error(err.to_string());
+        return;
+    }
+    if options.encryption.is_none() {
+        if let Some(expected) = encryption::source_encryption(origin) {
+            if let Err(err) = encryption::verify_raw_receive(destination, &expected) {
+                send_error(err.to_string());
+                return;
+            }
+        }
+    }
+
+    // only now may later sends build on this snapshot
+    let bookmark = path.replace('@', "#") + &format!("-sync-{}", cmd.name);
     println!("Creating bookmark {}.", bookmark);
-    origin.bookmark(&path, &bookmark);
+    origin.bookmark(path, &bookmark);
+
+    record_source(origin, destination, &cmd.name);
+    if let Some(properties) = &cmd.sync_props {
+        properties::sync_properties(origin, destination, properties);
+    }
+    if cmd.replicate_holds {
+        holds::replicate_holds(origin, destination);
+    }
 
     println!("Done.");
 }
 
+/// The pid of a `zfs receive` into `destination`'s dataset running on its
+/// host, if `ps` can tell.
+fn running_receive(destination: &Remote) -> Option<String> {
+    let mut cmd = destination.command("ps", &["-A", "-o", "pid=", "-o", "args="]);
+    trace(&[&cmd]);
+    let out = command_output(&mut cmd)
+        .ok()
+        .filter(|x| x.status.success())?;
+    // illumos cuts args short, which can hide a long dataset name there
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .find_map(|line| {
+            let (pid, args) = line.trim_start().split_once(' ')?;
+            let words = args.split_whitespace().collect::<Vec<_>>();
+            let receiving = words
+                .windows(2)
+                .any(|x| x[0].ends_with("zfs") && (x[1] == "recv" || x[1] == "receive"));
+            let into = words.last() == Some(&destination.dataset.as_str());
+            (receiving && into).then(|| pid.to_string())
+        })
+}
+
+/// Whether `--defer-during-scan` asks to skip this run, because either pool
+/// is being scrubbed or resilvered and `sends` (arguments to zfs send) are
+/// too large to share the disks with it.
+fn defer_for_scan(
+    origin: &Remote,
+    destination: &Remote,
+    threshold: Option<&str>,
+    sends: &[Vec<&str>],
+) -> bool {
+    let threshold = match threshold {
+        Some(x) => throttle::parse_bytes(x),
+        None => return false,
+    };
+    for remote in &[origin, destination] {
+        let scan = match health::scan_in_progress(remote) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("Warning: cannot tell whether a scrub is running: {}", err);
+                continue;
+            }
+        };
+        // a stream of unknown size counts as a large one
+        let size = sends
+            .iter()
+            .map(|x| estimate_size(origin, x).unwrap_or(u64::MAX))
+            .fold(0, u64::saturating_add);
+        if size <= threshold {
+            return false;
+        }
+        let size = match size {
+            u64::MAX => "an unknown amount".to_string(),
+            x => format_bytes(x),
+        };
+        println!(
+            "A {} of pool {} on {} is in progress and {} would be sent, deferring until it is done.",
+            scan,
+            remote.dataset.split('/').next().unwrap(),
+            remote.host(),
+            size
+        );
+        summary::finish("skipped");
+        return true;
+    }
+    false
+}
+
+/// Parses a keep specification such as `7d24h4f`, optionally followed by
+/// policies for labels, e.g. `7d24h4f3l,pre-upgrade=12m,manual=10l`.
 fn parse_spec(input: &str) -> Spec {
+    let mut parts = input.split(',');
+    let mut out = parse_counts(parts.next().unwrap());
+    if !out.has_slots() {
+        panic!("Cowardly refusing to keep nothing.");
+    }
+    for part in parts {
+        let (label, counts) = match part.split_once('=') {
+            Some(x) => x,
+            None => panic!("expected LABEL=SPEC after a comma, got {}", part),
+        };
+        check_label(label);
+        let spec = parse_counts(counts);
+        if !spec.has_slots() && spec.labeled.unwrap_or(0) == 0 {
+            panic!("Cowardly refusing to keep no {} snapshots.", label);
+        }
+        out.labels.push((label.to_string(), spec));
+    }
+    out
+}
+
+fn parse_counts(input: &str) -> Spec {
     let mut buf = String::new();
     let mut out = Spec {
         monthly: 0,
@@ -318,9 +2062,11 @@ fn parse_spec(input: &str) -> Spec {
         daily: 0,
         hourly: 0,
         frequently: 0,
+        labeled: None,
+        labels: Vec::new(),
     };
     for ch in input.chars() {
-        if ('0'..='9').contains(&ch) {
+        if ch.is_ascii_digit() {
             buf.push(ch);
             continue;
         }
@@ -333,173 +2079,1505 @@ fn parse_spec(input: &str) -> Spec {
             'd' => out.daily = num,
             'h' => out.hourly = num,
             'f' => out.frequently = num,
+            'l' => out.labeled = Some(num),
             _ => panic!("unrecognized duration {}", ch),
         }
     }
-    if out.monthly == 0
-        && out.weekly == 0
-        && out.daily == 0
-        && out.hourly == 0
-        && out.frequently == 0
-    {
-        panic!("Cowardly refusing to keep nothing.");
-    }
     out
 }
 
-fn parse_remote(input: &str) -> Remote {
-    let indicies = input.rmatch_indices(':').collect::<Vec<_>>();
-    if let Some((idx, _)) = indicies.first() {
-        Remote {
-            dataset: input[idx + 1..].to_string(),
-            transport: Transport::SSH(input[0..*idx].to_string()),
-        }
+/// Parses durations like `90s`, `5m`, `4h` or `1d`. A bare number is
+/// taken as seconds.
+fn parse_duration(input: &str) -> std::time::Duration {
+    let (digits, multiplier) = match input.chars().last() {
+        Some('s') => (&input[..input.len() - 1], 1),
+        Some('m') => (&input[..input.len() - 1], 60),
+        Some('h') => (&input[..input.len() - 1], 60 * 60),
+        Some('d') => (&input[..input.len() - 1], 24 * 60 * 60),
+        _ => (input, 1),
+    };
+    let num = digits
+        .parse::<u64>()
+        .unwrap_or_else(|_| panic!("invalid duration {}", input));
+    std::time::Duration::from_secs(num * multiplier)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        Remote {
-            dataset: input.to_string(),
-            transport: Transport::Local,
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+struct Window {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+}
+
+impl Window {
+    fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // window wraps around midnight, e.g. 22:00-04:00
+            time >= self.start || time < self.end
         }
     }
 }
 
+fn parse_window(input: &str) -> Window {
+    let parts = input.split('-').collect::<Vec<_>>();
+    if parts.len() != 2 {
+        panic!("invalid window {}, expected HH:MM-HH:MM", input);
+    }
+    let parse_time = |x: &str| {
+        chrono::NaiveTime::parse_from_str(x, "%H:%M")
+            .unwrap_or_else(|_| panic!("invalid time {} in window", x))
+    };
+    Window {
+        start: parse_time(parts[0]),
+        end: parse_time(parts[1]),
+    }
+}
+
+/// Splits a location into how to reach it and the dataset part, accepting
+/// `dataset`, `[user@]host:dataset`, `[user@][v6addr]:dataset` and
+/// `ssh://[user@]host[:port]/dataset`. A `/` before the first `:` means a
+/// local dataset.
+fn split_remote(input: &str) -> (Transport, &str) {
+    if let Some(rest) = input.strip_prefix("ssh://") {
+        let (authority, dataset) = match rest.split_once('/') {
+            Some(x) => x,
+            None => panic!("expected ssh://[user@]host[:port]/dataset, got {}", input),
+        };
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, x)) => (Some(user), x),
+            None => (None, authority),
+        };
+        let (host, port) = if let Some(v6) = host_port.strip_prefix('[') {
+            match v6.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) if port.starts_with(':') => (host, Some(&port[1..])),
+                _ => panic!("unterminated IPv6 address in {}", input),
+            }
+        } else {
+            match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            }
+        };
+        if host.is_empty() {
+            panic!("missing host in {}", input);
+        }
+        let port = port.map(|x| {
+            x.parse::<u16>()
+                .unwrap_or_else(|_| panic!("invalid port {} in {}", x, input))
+        });
+        let target = match user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_string(),
+        };
+        return (Transport::SSH(SshHost { target, port }), dataset);
+    }
+
+    // an IPv6 address contains colons, so it is bracketed like with scp
+    let user_len = input
+        .find('@')
+        .filter(|&at| input[at + 1..].starts_with('[') && !input[..at].contains(['/', ':']));
+    let open = user_len.map_or(0, |at| at + 1);
+    if input[open..].starts_with('[') {
+        return match input[open..].split_once("]:") {
+            Some((host, dataset)) => {
+                let target = format!("{}{}", &input[..open], &host[1..]);
+                (Transport::SSH(SshHost { target, port: None }), dataset)
+            }
+            None => panic!("expected [address]:dataset, got {}", input),
+        };
+    }
+
+    match input.split_once(':') {
+        Some((host, dataset)) if !host.is_empty() && !host.contains('/') => (
+            Transport::SSH(SshHost {
+                target: host.to_string(),
+                port: None,
+            }),
+            dataset,
+        ),
+        _ => (Transport::Local, input),
+    }
+}
+
+fn parse_remote(input: &str) -> Remote {
+    let (transport, dataset) = split_remote(input);
+    // what zfs allows in names, plus the glob characters of `select`
+    let allowed = |ch: char| ch.is_ascii_alphanumeric() || "_-.: /@#%*?[]!".contains(ch);
+    if let Some(ch) = dataset.chars().find(|&ch| !allowed(ch)) {
+        panic!("invalid character {:?} in dataset {:?}", ch, dataset);
+    }
+    // ssh would take it for an option
+    if let Transport::SSH(host) = &transport {
+        if host.target.starts_with('-') {
+            panic!("invalid host {:?}", host.target);
+        }
+    }
+    Remote {
+        dataset: dataset.to_string(),
+        transport,
+    }
+}
+
 fn main() {
     let app = App::parse();
+    signal::install();
+    if let Some(path) = &app.summary_file {
+        summary::enable(path);
+    }
+    if let Subcommand::Send(cmd) = &app.subcmd {
+        // before taking the lock, so that other runs aren't held up
+        if let Some(jitter) = &cmd.jitter {
+            let delay = jitter_delay(&cmd.name, parse_duration(jitter));
+            println!("Waiting {}s before sending.", delay.as_secs());
+            if !sleep(delay) {
+                eprintln!("Interrupted.");
+                std::process::exit(130);
+            }
+        }
+    }
+    let lock = match app.subcmd {
+        Subcommand::Watch(_)
+        | Subcommand::Man(_)
+        | Subcommand::Bench(_)
+        | Subcommand::Status(_)
+        | Subcommand::Chain(_)
+        | Subcommand::DrPlan(_)
+        | Subcommand::Compare(_)
+        | Subcommand::Audit(_)
+        | Subcommand::Catalog(_) => None,
+        // snapshots keep being taken while a send is busy or stuck
+        Subcommand::Snap(_) => Some(state::lock_instance(
+            state::LockScope::Shared("snap"),
+            app.wait,
+        )),
+        Subcommand::Send(ref cmd) => Some(state::lock_instance(
+            state::LockScope::Shared(&format!("send {}", cmd.name)),
+            app.wait,
+        )),
+        _ => Some(state::lock_instance(state::LockScope::Exclusive, app.wait)),
+    };
+    if lock.is_some() {
+        progress::spawn_status_reporter();
+    }
 
-    let now = chrono::Utc::now();
-    match app.subcmd {
-        Subcommand::Snap(cmd) => {
-            let origin = parse_remote(&cmd.location);
+    TRACE.store(app.trace, Ordering::Relaxed);
+    PROTECTED
+        .set(
+            app.protect
+                .iter()
+                .map(|x| select::glob_regex(x.trim_start_matches('@')))
+                .collect(),
+        )
+        .ok();
+    if let Some(timeout) = &app.timeout {
+        COMMAND_TIMEOUT.store(parse_duration(timeout).as_secs(), Ordering::Relaxed);
+    }
+    platform::set_hints(&app.platform);
+    if app.rsh.is_some() && app.ssh_jump.is_some() {
+        panic!("--ssh-jump has no effect with --rsh, add -J to the --rsh command instead");
+    }
+    SSH_SETTINGS
+        .set(SshSettings {
+            jump: app.ssh_jump.clone(),
+            rsh: app
+                .rsh
+                .iter()
+                .flat_map(|x| x.split_whitespace())
+                .map(|x| x.to_string())
+                .collect(),
+            // leave connection sharing to ssh options that ask for their own
+            control_dir: match std::env::var("ZBAK_SSH_OPTS") {
+                Ok(opts) if opts.contains("Control") => None,
+                _ if app.rsh.is_some() => None,
+                _ => Some(state::state_dir()),
+            },
+        })
+        .ok();
+
+    // the panic was already printed, the summary should have it too
+    if let Err(payload) = panic::catch_unwind(panic::AssertUnwindSafe(|| run(app))) {
+        summary::write(101, Some(&panic_message(payload)));
+        std::process::exit(101);
+    }
 
-            let snapshots = origin.list_snapshots();
-            let should_snapshot = if let Some(last) = snapshots.last() { 
-                now.sub(last.time) > chrono::Duration::minutes(14)
-            } else {
-                true
-            };
-            if should_snapshot {
-                let now_tag = now.format("%Y-%m-%dT%H%M");
-                let path = format!("{}@{}", origin.dataset, now_tag);
-                println!("Creating snapshot {}.", path);
-                origin.snapshot(&path);
+    if signal::interrupted() {
+        drop(lock);
+        eprintln!("Interrupted.");
+        exit(130);
+    }
+    summary::write(0, None);
+}
+
+/// Exits with `status`, writing the summary file first.
+fn exit(status: i32) -> ! {
+    summary::write(status, None);
+    std::process::exit(status)
+}
+
+/// What a caught panic was about, e.g. a failed zfs command.
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown error".to_string()
+    }
+}
+
+/// A delay below `max` that differs between machines and jobs but stays
+/// the same from run to run, so each machine keeps a predictable slot.
+fn jitter_delay(job: &str, max: std::time::Duration) -> std::time::Duration {
+    use std::hash::{Hash, Hasher};
+
+    let mut hostname = [0u8; 256];
+    unsafe { libc::gethostname(hostname.as_mut_ptr() as *mut libc::c_char, hostname.len()) };
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    hostname.hash(&mut hasher);
+    job.hash(&mut hasher);
+    match max.as_secs() {
+        0 => max,
+        secs => std::time::Duration::from_secs(hasher.finish() % secs),
+    }
+}
+
+/// Sleeps for `duration`, returning `false` early if a termination signal
+/// arrives.
+fn sleep(duration: std::time::Duration) -> bool {
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        if signal::interrupted() {
+            return false;
+        }
+        std::thread::sleep((end - Instant::now()).min(std::time::Duration::from_secs(1)));
+    }
+    !signal::interrupted()
+}
+
+/// Snapshots and prunes `origins`. The datasets are snapshotted together
+/// in one `zfs snapshot` and share the snapshot's name.
+fn snap(origins: &[Remote], cmd: &SnapCommand, now: &chrono::DateTime<Utc>) {
+    let names = origins
+        .iter()
+        .map(|x| x.dataset.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut progress = progress::Progress::new(&format!("snap {}", names), None);
+    progress.set_phase("listing");
+
+    let listed = origins
+        .iter()
+        .map(|origin| origin.list_snapshots())
+        .collect::<Vec<_>>();
+    // after the clock stepped back, new snapshots would sort before older
+    // ones and retention would prune the wrong ones
+    if !cmd.allow_clock_skew {
+        let limit = *now + chrono::Duration::minutes(1);
+        if let Some(last) = listed
+            .iter()
+            .filter_map(|x| x.last())
+            .find(|x| x.time > limit)
+        {
+            panic!(
+                "{} was created at {}, after the current time {}; check the clocks, \
+                 or pass --allow-clock-skew to snapshot and prune anyway",
+                last.path,
+                last.time.format("%Y-%m-%d %H:%M:%S UTC"),
+                now.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+        }
+    }
+
+    // a group stays in step, so one stale member snapshots all of them;
+    // labeled snapshots are taken on demand and don't count
+    let should_snapshot = cmd.label.is_some()
+        || listed.iter().any(|snapshots| {
+            match snapshots
+                .iter()
+                .rev()
+                .find(|x| snapshot_label(&x.path).is_none())
+            {
+                Some(last) => now.sub(last.time) > chrono::Duration::minutes(14),
+                None => true,
+            }
+        });
+    if should_snapshot {
+        let paths_named = |format: &str| {
+            let mut now_tag = now.format(format).to_string();
+            if let Some(label) = &cmd.label {
+                now_tag = format!("{}-{}", now_tag, label);
+            }
+            origins
+                .iter()
+                .map(|x| format!("{}@{}", x.dataset, now_tag))
+                .collect::<Vec<_>>()
+        };
+        let paths = paths_named(name_format(&cmd.name_format));
+        progress.set_phase("snapshotting");
+        let (pre_snapshot, post_snapshot) = match &cmd.hook {
+            Some(_) if cmd.pre_snapshot.is_some() || cmd.post_snapshot.is_some() => {
+                panic!("--hook replaces --pre-snapshot and --post-snapshot, pass one or the other")
+            }
+            Some(preset) => hooks::preset(preset),
+            None => (cmd.pre_snapshot.clone(), cmd.post_snapshot.clone()),
+        };
+        // a group's hooks run once, told about its first dataset
+        if let Some(hook) = &pre_snapshot {
+            if !hooks::run("pre-snapshot", hook, &paths[0]) {
+                panic!("pre-snapshot hook failed, not snapshotting {}", names);
+            }
+        }
+        for path in &paths {
+            println!("Creating snapshot {}.", path);
+        }
+        let take = |paths: &[String]| {
+            origins[0].snapshots(&paths.iter().map(|x| x.as_str()).collect::<Vec<_>>())
+        };
+        let created = panic::catch_unwind(panic::AssertUnwindSafe(|| match take(&paths) {
+            Ok(()) => {}
+            // a manual snapshot, or a second run within the minute, took the
+            // name; the time to the second tells them apart
+            Err(e) if e.contains("dataset already exists") => {
+                let retry = paths_named(name_format("seconds"));
+                if retry == paths {
+                    panic!("cmd err: {}", e);
+                }
+                println!(
+                    "Warning: {} already exists, naming the snapshot {} instead.",
+                    paths[0], retry[0]
+                );
+                take(&retry).unwrap();
+            }
+            Err(e) => panic!("cmd err: {}", e),
+        }));
+        // thaw whatever the pre-snapshot hook froze, whatever happened
+        if let Some(hook) = &post_snapshot {
+            if !hooks::run("post-snapshot", hook, &paths[0]) {
+                println!("Warning: post-snapshot hook failed.");
             }
+        }
+        if let Err(err) = created {
+            panic::resume_unwind(err);
+        }
 
-            let spec = parse_spec(&cmd.keep);
+        if let Some(factor) = cmd.churn_alert {
+            for origin in origins {
+                churn::check_churn(origin, factor);
+            }
+        }
+    }
 
-            let snapshots = origin.list_snapshots();
-            let prunable = find_prunable(&now, &spec, snapshots).remove;
-            for snapshot in prunable {
-                println!("Removing {}.", snapshot.path);
-                origin.destroy_snapshot(&snapshot.path);
+    let spec = parse_spec(&cmd.keep);
+
+    progress.set_phase("pruning");
+    for origin in origins {
+        let synced = origin.latest_sync_bookmarks();
+        let bases = synced.values().map(|x| x.guid.clone()).collect::<Vec<_>>();
+        // the destination each job replicates to, only needed for
+        // --min-copies; a job that hasn't recorded one counts on its own
+        let replicas = synced
+            .keys()
+            .filter(|_| cmd.min_copies > 0)
+            .map(|job| {
+                let replica = recorded_destination(origin, job).unwrap_or_else(|| job.clone());
+                (job.clone(), replica)
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+        let snapshots = origin.list_snapshots();
+        let mut plan = find_prunable(now, &spec, snapshots);
+        for snapshot in plan.protect(&bases) {
+            println!("Keeping {}, the base of the next send.", snapshot.path);
+        }
+        for snapshot in plan.remove {
+            if signal::interrupted() {
+                return;
+            }
+            if is_protected(&snapshot.path) {
+                println!("Keeping protected {}.", snapshot.path);
+                continue;
             }
+            let pending = cmd
+                .replicated_by
+                .iter()
+                .find(|job| synced.get(*job).is_none_or(|x| snapshot.time > x.time));
+            if let Some(job) = pending {
+                println!(
+                    "Keeping {}, {} has not replicated it yet.",
+                    snapshot.path, job
+                );
+                continue;
+            }
+            // two jobs replicating to the same dataset are one copy
+            let copies = synced
+                .iter()
+                .filter(|(_, x)| snapshot.time <= x.time)
+                .map(|(job, _)| replicas.get(job).unwrap_or(job))
+                .collect::<std::collections::HashSet<_>>()
+                .len();
+            if copies < cmd.min_copies {
+                println!(
+                    "Keeping {}, replicated to {} of the {} destinations --min-copies requires.",
+                    snapshot.path, copies, cmd.min_copies
+                );
+                continue;
+            }
+            println!("Removing {}.", snapshot.path);
+            origin.prune_snapshot(&snapshot.path, cmd.release_holds.as_deref());
         }
-        Subcommand::Send(cmd) => {
-            let destination_spec = parse_spec(&cmd.keep);
+    }
+}
 
-            let origin = parse_remote(&cmd.from);
-            let destination = parse_remote(&cmd.to);
+/// Applies retention to a replica's snapshots.
+fn prune_destination(
+    destination: &Remote,
+    spec: &Spec,
+    now: &chrono::DateTime<Utc>,
+    release_holds: Option<&str>,
+    dry_run: bool,
+) {
+    let destination_snapshots = destination.list_snapshots();
+    // the next incremental receive needs it, however old it is
+    let base = destination_snapshots.last().map(|x| x.guid.clone());
+    let mut destination_plan = find_prunable(now, spec, destination_snapshots);
+    for snapshot in destination_plan.protect(base.as_slice()) {
+        println!(
+            "Keeping remote's {}, the base of the next send.",
+            snapshot.path
+        );
+    }
+    let clones = destination.dependent_clones();
+    for snapshot in destination_plan.remove {
+        if signal::interrupted() {
+            return;
+        }
+        if let Some(clones) = clones.get(&snapshot.path) {
+            println!(
+                "Warning: keeping remote's {}, clone(s) {} depend on it.",
+                snapshot.path, clones
+            );
+            continue;
+        }
+        if is_protected(&snapshot.path) {
+            println!("Keeping protected {}.", snapshot.path);
+            continue;
+        }
+        if dry_run {
+            println!("Would prune remote's snapshot {}", snapshot.path);
+            continue;
+        }
+        println!("Pruning remote's snapshot {}", snapshot.path);
+        destination.prune_snapshot(&snapshot.path, release_holds);
+    }
+}
 
-            let mut origin_bookmarks = origin.list_bookmarks(&cmd.name);
-            origin_bookmarks.sort_by(|a, b| a.time.cmp(&b.time));
+/// Panics unless `datasets` can be snapshotted in one `zfs snapshot`, which
+/// only works within a single pool.
+fn check_group(datasets: &[Remote]) {
+    let pool = |x: &Remote| x.dataset.split('/').next().unwrap().to_string();
+    for dataset in &datasets[1..] {
+        if dataset.transport != datasets[0].transport || pool(dataset) != pool(&datasets[0]) {
+            panic!(
+                "{} and {} are not in the same pool, they cannot be snapshotted as a group",
+                datasets[0].dataset, dataset.dataset
+            );
+        }
+    }
+}
 
-            let bookmark = match origin_bookmarks.last() {
-                Some(x) => x,
-                None => {
-                    send_nonincremental(&origin, &destination, &cmd.name);
-                    return;
-                }
-            };
+/// Reports why a send gave up, also in the summary file.
+fn send_error(message: String) {
+    println!("Error: {}", message);
+    summary::fail(&message);
+}
 
-            println!("Using bookmark {}.", bookmark.path);
-
-            let mut snapshots_to_send = {
-                let new_origin_snapshots = origin
-                    .list_snapshots()
-                    .into_iter()
-                    .filter(|x| x.time > bookmark.time)
-                    .collect::<Vec<_>>();
-                find_prunable(&now, &destination_spec, new_origin_snapshots)
-                    .keep
-                    .into_iter()
-                    .filter(|x| is_normal_snapshot(&x.path))
-                    .collect::<Vec<_>>()
-            };
+/// How far a send got, unless it failed.
+#[derive(Clone, Copy, PartialEq)]
+enum SendOutcome {
+    /// Nothing was left to send, or the run was skipped or interrupted
+    Finished,
+    /// --max-runtime ran out with increments left for the next run
+    Partial,
+}
 
-            snapshots_to_send.sort_by(|a, b| a.time.cmp(&b.time));
+/// Replicates new snapshots from `from` to `to`, e.g. `cmd.from` and
+/// `cmd.to`, and prunes both sides, bookmarking each snapshot once it
+/// arrived. Deadlines count from `started`, when the run began. Failures
+/// are reported with send_error, which marks the job failed. The history
+/// knows the run as `job`, which names the dataset under --recursive.
+fn send(
+    cmd: &SendCommand,
+    job: &str,
+    from: &str,
+    to: &str,
+    started: Instant,
+    now: &chrono::DateTime<Utc>,
+    wait: bool,
+) -> SendOutcome {
+    let destination_spec = parse_spec(&cmd.keep);
+    if let Some(mode) = &cmd.pool_health {
+        if mode != "abort" && mode != "warn" {
+            panic!("unknown --pool-health {}, expected abort or warn", mode);
+        }
+    }
 
-            if snapshots_to_send.is_empty() {
-                println!("Nothing to send.");
-                return;
+    if let Some(window) = &cmd.window {
+        let local_time = now.with_timezone(&chrono::Local).time();
+        if !parse_window(window).contains(local_time) {
+            println!("Outside of replication window {}, skipping.", window);
+            summary::finish("skipped");
+            return SendOutcome::Finished;
+        }
+    }
+
+    let options = TransferOptions {
+        bwlimit: cmd.bwlimit.as_deref().map(throttle::parse_schedule),
+        deadline: cmd.deadline.as_deref().map(|x| started + parse_duration(x)),
+        encryption: cmd
+            .encrypt
+            .as_deref()
+            .map(|x| encryption::Encryption::new(x, &cmd.keyformat)),
+        nice: cmd.nice,
+        ionice: cmd.ionice.clone(),
+        send_opts: split_opts(&cmd.send_opts),
+        recv_opts: split_opts(&cmd.recv_opts),
+    };
+
+    let budget_end = cmd
+        .max_runtime
+        .as_deref()
+        .map(|x| started + parse_duration(x));
+
+    let mut progress = progress::Progress::new(
+        &format!("send {} -> {}", from, to),
+        cmd.progress_json.as_deref().map(progress::open_events),
+    );
+    progress.set_phase("listing");
+
+    let origin = parse_remote(from);
+    let destination = parse_remote(to);
+
+    if cmd.ionice.is_some() {
+        for remote in [&origin, &destination] {
+            if platform::of(remote) != platform::Platform::Linux {
+                println!(
+                    "Warning: {} has no ionice, running zfs there without it.",
+                    remote.host()
+                );
             }
+        }
+    }
+
+    if let Err(err) = check_source(&origin, &destination) {
+        if !cmd.replace_dest {
+            send_error(format!(
+                "{}, pass --replace-dest to replicate it anyway.",
+                err
+            ));
+            return SendOutcome::Finished;
+        }
+        println!("Warning: {}, replicating it anyway.", err);
+    }
 
-            let dest_snapshots = destination.list_snapshots();
-            for snapshot in dest_snapshots.iter().filter(|x| x.time > bookmark.time) {
-                println!("Destroying destination's {}.", snapshot.path);
-                destination.destroy_snapshot(&snapshot.path);
+    if let Err(err) = check_unchanged(&destination) {
+        if !cmd.force {
+            send_error(format!("{} Pass --force to discard the changes.", err));
+            return SendOutcome::Finished;
+        }
+        println!("Warning: {} Discarding the changes.", err);
+    }
+
+    // the instance lock only covers this machine; a run from elsewhere, or
+    // one whose ssh connection dropped, may still be receiving
+    let mut waiting = false;
+    while let Some(pid) = running_receive(&destination) {
+        if !wait {
+            println!(
+                "A zfs receive into {} is still running on {} (pid {}), the previous run is still in progress; skipping. Pass --wait to queue behind it.",
+                destination.dataset,
+                destination.host(),
+                pid
+            );
+            summary::finish("skipped");
+            return SendOutcome::Finished;
+        }
+        if !waiting {
+            println!(
+                "A zfs receive into {} is still running on {} (pid {}), waiting.",
+                destination.dataset,
+                destination.host(),
+                pid
+            );
+            waiting = true;
+        }
+        if !sleep(std::time::Duration::from_secs(30)) {
+            return SendOutcome::Finished;
+        }
+    }
+
+    if let Some(mode) = &cmd.pool_health {
+        for remote in &[&origin, &destination] {
+            if let Err(err) = health::check_pool(remote) {
+                if mode == "warn" {
+                    println!("Warning: {}.", err);
+                } else {
+                    send_error(format!("{}, not replicating.", err));
+                    return SendOutcome::Finished;
+                }
             }
+        }
+    }
 
-            let send_paths = snapshots_to_send
-                .into_iter()
-                .map(|x| x.path)
-                .collect::<Vec<_>>();
+    let origin_bookmarks = origin.list_bookmarks(&cmd.name);
 
-            println!("Sending:");
-            for path in &send_paths {
-                println!("- {}", path);
+    // without bookmarks there is no chain to break, a full send starts anew
+    if !origin_bookmarks.is_empty() {
+        if let Err(err) = check_destination(&origin, &destination, &cmd.name) {
+            send_error(err);
+            return SendOutcome::Finished;
+        }
+    }
+
+    let bookmark = match origin_bookmarks.last() {
+        Some(x) => x,
+        None => {
+            send_nonincremental(&origin, &destination, cmd, job, &options, &mut progress);
+            return SendOutcome::Finished;
+        }
+    };
+
+    println!("Using bookmark {}.", bookmark.path);
+
+    let new_origin_snapshots = origin
+        .list_snapshots()
+        .into_iter()
+        .filter(|x| x.time > bookmark.time);
+    let snapshots_to_send = find_prunable(now, &destination_spec, new_origin_snapshots).keep;
+
+    if snapshots_to_send.is_empty() {
+        println!("Nothing to send.");
+        // holds may have been placed since the last sync
+        if cmd.replicate_holds {
+            holds::replicate_holds(&origin, &destination);
+        }
+        return SendOutcome::Finished;
+    }
+
+    let mut prev = bookmark.path.as_str();
+    let mut sends = Vec::new();
+    for snapshot in &snapshots_to_send {
+        sends.push(vec!["send", "-i", prev, &snapshot.path]);
+        prev = &snapshot.path;
+    }
+    if defer_for_scan(
+        &origin,
+        &destination,
+        cmd.defer_during_scan.as_deref(),
+        &sends,
+    ) {
+        return SendOutcome::Finished;
+    }
+
+    if cmd.changes {
+        // zfs diff needs the snapshot the bookmark was made from
+        let base = origin
+            .internal_list("snapshot")
+            .into_iter()
+            .find(|x| x.guid == bookmark.guid);
+        match base {
+            Some(base) => {
+                changes::print_changes(&origin, &base.path, &snapshots_to_send.last().unwrap().path)
             }
+            None => println!(
+                "Warning: cannot list changes, the snapshot of {} no longer exists.",
+                bookmark.path
+            ),
+        }
+    }
 
-            let mut first = true;
-            let mut prev = bookmark.path.clone();
-            for path in send_paths {
-                println!("Sending {} -> {}.", prev, path);
+    let dest_snapshots = destination.list_snapshots();
+    let newer = dest_snapshots
+        .iter()
+        .filter(|x| x.time > bookmark.time)
+        .collect::<Vec<_>>();
+    if let Some(snapshot) = newer.iter().find(|x| is_protected(&x.path)) {
+        send_error(format!(
+            "destination's {} is newer than the last sync but protected, refusing to roll back.",
+            snapshot.path
+        ));
+        return SendOutcome::Finished;
+    }
+    let clones = destination.dependent_clones();
+    if let Some(snapshot) = newer.iter().find(|x| clones.contains_key(&x.path)) {
+        send_error(format!("destination's {} is newer than the last sync, but clone(s) {} depend on it, so it can't be rolled back. Destroy them first, e.g. with zbak clone --cleanup.",
+            snapshot.path, clones[&snapshot.path]
+        ));
+        return SendOutcome::Finished;
+    }
+    for snapshot in newer {
+        println!("Destroying destination's {}.", snapshot.path);
+        destination.destroy_snapshot(&snapshot.path);
+    }
 
-                let flags = if first { "-wi" } else { "-wI" };
+    let send_paths = snapshots_to_send
+        .into_iter()
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
 
-                let mut producer = origin
-                    .cmd(&["send", flags, &prev, &path])
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .unwrap();
+    println!("Sending:");
+    for path in &send_paths {
+        println!("- {}", path);
+    }
 
-                let consumer = destination
-                    .cmd(&["recv", "-u", &destination.dataset])
-                    .stdin(producer.stdout.take().unwrap())
-                    .spawn()
-                    .unwrap();
+    if options.encryption.is_some() {
+        // non-raw streams are encrypted by the receiver, which
+        // needs the replica's key for that
+        encryption::ensure_key_loaded(
+            &destination,
+            &destination.dataset,
+            &encryption::KeySource::Prompt,
+        );
+    }
 
-                let out_consumer = consumer.wait_with_output().unwrap();
-                if !out_consumer.status.success() {
-                    println!("Error: {:?}", out_consumer);
-                    return;
-                }
+    // raw streams keep the source's encryption, check it survived
+    let raw_encryption = match options.encryption {
+        Some(_) => None,
+        None => encryption::source_encryption(&origin),
+    };
+
+    let total = send_paths.len();
+    let mut sent = 0;
+    let mut sent_bytes = 0;
+    let mut sending = std::time::Duration::ZERO;
+    // -F rolls back whatever --force allows to be discarded
+    let mut recv_args = vec!["recv", "-u"];
+    if cmd.force {
+        recv_args.push("-F");
+    }
+    recv_args.push(&destination.dataset);
+
+    let mut first = true;
+    let mut prev = bookmark.path.clone();
+    for path in send_paths {
+        if signal::interrupted() {
+            return SendOutcome::Finished;
+        }
+        if budget_end.is_some_and(|x| Instant::now() > x) {
+            break;
+        }
+        println!("Sending {} -> {}.", prev, path);
 
-                let out_producer = producer.wait_with_output().unwrap();
-                if !out_producer.status.success() {
-                    println!("Error: {:?}", out_producer);
-                    return;
+        let flags = match (first, options.encryption.is_some()) {
+            (true, false) => "-wi",
+            (false, false) => "-wI",
+            (true, true) => "-i",
+            (false, true) => "-I",
+        };
+
+        let started = Instant::now();
+        match transfer(
+            &origin,
+            &["send", flags, &prev, &path],
+            &destination,
+            &recv_args,
+            &options,
+            &mut progress,
+        ) {
+            Ok(bytes) => {
+                sent_bytes += bytes;
+                sending += started.elapsed();
+            }
+            Err(err) => {
+                if !matches!(err, TransferError::Interrupted) {
+                    send_error(err.to_string());
                 }
+                return SendOutcome::Finished;
+            }
+        }
+
+        if let Err(err) = verify_received(&origin, &destination, &path) {
+            send_error(err.to_string());
+            return SendOutcome::Finished;
+        }
+        if let Some(expected) = &raw_encryption {
+            if let Err(err) = encryption::verify_raw_receive(&destination, expected) {
+                send_error(err.to_string());
+                return SendOutcome::Finished;
+            }
+        }
+
+        origin.bookmark(&path, &(path.replace('@', "#") + "-sync-" + &cmd.name));
+
+        prev = path;
+        first = false;
+        sent += 1;
+    }
 
-                origin.bookmark(&path, &(path.replace('@', "#") + "-sync-" + &cmd.name));
+    history::record(job, "incremental", sent_bytes, sending);
+    progress.set_phase("pruning");
+
+    let mut origin_bookmarks = origin.list_bookmarks(&cmd.name);
+    origin_bookmarks.pop(); // remove latest bookmark
+    for bookmark in origin_bookmarks {
+        println!("Pruning origin's bookmark {}", bookmark.path);
+        origin.destroy_bookmark(&bookmark.path);
+    }
+
+    // before pruning, which leaves held snapshots alone
+    if cmd.replicate_holds {
+        holds::replicate_holds(&origin, &destination);
+    }
+    prune_destination(
+        &destination,
+        &destination_spec,
+        now,
+        cmd.release_holds.as_deref(),
+        false,
+    );
+
+    record_source(&origin, &destination, &cmd.name);
+    if let Some(properties) = &cmd.sync_props {
+        properties::sync_properties(&origin, &destination, properties);
+    }
+
+    if sent < total {
+        println!(
+            "Stopped after {} of {} increments, --max-runtime {} is used up. The next run continues from {}.",
+            sent,
+            total,
+            cmd.max_runtime.as_deref().unwrap(),
+            prev
+        );
+        summary::finish("partial");
+        return SendOutcome::Partial;
+    }
+    println!("Done.");
+    SendOutcome::Finished
+}
+
+/// Where a dataset of a recursive send is at.
+#[derive(Clone, Copy, PartialEq)]
+enum TreeState {
+    Waiting,
+    Sending,
+    Sent,
+    /// Sent as far as --max-runtime allowed
+    Partial,
+    Failed,
+    Skipped,
+}
 
-                prev = path;
-                first = false;
+/// Sends `cmd.from` and the datasets below it, except those excluded, each
+/// to the same place below `cmd.to`, up to `cmd.jobs` at a time. A dataset
+/// is sent once the one it is below arrived; if that failed, it is skipped,
+/// having nowhere to be received.
+fn send_tree(cmd: &SendCommand, now: &chrono::DateTime<Utc>, wait: bool) {
+    if cmd.jobs == 0 {
+        panic!("--jobs must be at least 1");
+    }
+    let started = Instant::now();
+    let root = parse_remote(&cmd.from);
+    let tree = select::tree(&root, &cmd.exclude);
+    // the dataset always comes last in a location
+    let suffixes = tree
+        .iter()
+        .map(|x| x.dataset[root.dataset.len()..].to_string())
+        .collect::<Vec<_>>();
+    // parents are listed first, and excluding one excludes what is below it
+    let parents = suffixes
+        .iter()
+        .map(|suffix| {
+            let parent = &suffix[..suffix.rfind('/')?];
+            suffixes.iter().position(|x| x == parent)
+        })
+        .collect::<Vec<_>>();
+    let mut states = vec![TreeState::Waiting; tree.len()];
+
+    std::thread::scope(|scope| {
+        let (done, finished) = std::sync::mpsc::channel();
+        let mut running = 0;
+        loop {
+            for i in 0..tree.len() {
+                if states[i] != TreeState::Waiting {
+                    continue;
+                }
+                match parents[i].map(|x| states[x]) {
+                    Some(TreeState::Failed) | Some(TreeState::Skipped) => {
+                        states[i] = TreeState::Skipped;
+                        continue;
+                    }
+                    // a partial send still created the parent
+                    None | Some(TreeState::Sent) | Some(TreeState::Partial) => {}
+                    _ => continue,
+                }
+                if running == cmd.jobs || signal::interrupted() {
+                    continue;
+                }
+                let from = format!("{}{}", cmd.from, suffixes[i]);
+                let to = format!("{}{}", cmd.to, suffixes[i]);
+                let job = format!("{} {}", cmd.name, tree[i].dataset);
+                let done = done.clone();
+                states[i] = TreeState::Sending;
+                running += 1;
+                scope.spawn(move || {
+                    println!("Sending {} to {}.", from, to);
+                    summary::start(&job);
+                    // errors panic, keep them from taking down the other datasets
+                    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        send(cmd, &job, &from, &to, started, now, wait)
+                    }));
+                    let state = match result {
+                        Err(payload) => {
+                            summary::fail(&panic_message(payload));
+                            TreeState::Failed
+                        }
+                        Ok(_) if summary::failed() => TreeState::Failed,
+                        Ok(SendOutcome::Partial) => TreeState::Partial,
+                        Ok(SendOutcome::Finished) => {
+                            summary::finish("ok");
+                            TreeState::Sent
+                        }
+                    };
+                    done.send((i, state)).unwrap();
+                });
             }
+            if running == 0 {
+                break;
+            }
+            let (i, state) = finished.recv().unwrap();
+            running -= 1;
+            states[i] = state;
+        }
+    });
 
-            let mut origin_bookmarks = origin.list_bookmarks(&cmd.name);
-            origin_bookmarks.sort_by(|a,b| a.time.cmp(&b.time));
-            origin_bookmarks.pop(); // remove latest bookmark
-            for bookmark in origin_bookmarks {
-                println!("Pruning origin's bookmark {}", bookmark.path);
-                origin.destroy_bookmark(&bookmark.path);
+    if signal::interrupted() {
+        return;
+    }
+    let failed = tree
+        .iter()
+        .zip(&states)
+        .filter(|x| *x.1 == TreeState::Failed)
+        .map(|x| x.0.dataset.as_str())
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        let skipped = states.iter().filter(|x| **x == TreeState::Skipped).count();
+        println!(
+            "Failed to send {} dataset(s): {}{}",
+            failed.len(),
+            failed.join(", "),
+            match skipped {
+                0 => String::new(),
+                n => format!(", skipped {} below them", n),
             }
+        );
+        exit(1);
+    }
+    if states.contains(&TreeState::Partial) {
+        // like snap, 2 tells a scheduler the job is only partly done
+        exit(2);
+    }
+}
 
-            let destination_snapshots = destination.list_snapshots();
-            let destination_plan = find_prunable(&now, &destination_spec, destination_snapshots);
-            for snapshot in destination_plan.remove {
-                println!("Pruning remote's snapshot {}", snapshot.path);
-                destination.destroy_snapshot(&snapshot.path);
+fn run(app: App) {
+    #[cfg(feature = "replay")]
+    let now = replay::now();
+    #[cfg(not(feature = "replay"))]
+    let now = chrono::Utc::now();
+    match app.subcmd {
+        Subcommand::Snap(cmd) => {
+            // a typo should fail once, not once per dataset and retry
+            name_format(&cmd.name_format);
+            if let Some(label) = &cmd.label {
+                check_label(label);
+            }
+            let datasets = cmd
+                .location
+                .iter()
+                .flat_map(|x| select::expand(&parse_remote(x), &cmd.exclude))
+                .collect::<Vec<_>>();
+            if datasets.is_empty() {
+                println!("No datasets match {}.", cmd.location.join(" "));
             }
+            // datasets are retried, and fail, as a unit with their group
+            let mut pending = if cmd.group && !datasets.is_empty() {
+                check_group(&datasets);
+                vec![datasets]
+            } else {
+                datasets.into_iter().map(|x| vec![x]).collect()
+            };
+            let mut succeeded = 0;
+            let mut denied = Vec::new();
+            for attempt in 0..=cmd.retries {
+                if attempt > 0 {
+                    // a fail-fast run stops at its first failure
+                    if pending.is_empty() || cmd.fail_fast {
+                        break;
+                    }
+                    println!(
+                        "Retrying {} failed dataset(s) in {}.",
+                        pending.iter().flatten().count(),
+                        cmd.retry_delay
+                    );
+                    if !sleep(parse_duration(&cmd.retry_delay)) {
+                        return;
+                    }
+                }
+                let mut failed = Vec::new();
+                for origins in pending {
+                    if signal::interrupted() {
+                        return;
+                    }
+                    summary::start(
+                        &origins
+                            .iter()
+                            .map(|x| x.dataset.as_str())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    );
+                    // errors panic, keep them from taking down the other datasets
+                    let result = std::panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                        snap(&origins, &cmd, &now)
+                    }));
+                    match result {
+                        Ok(()) => {
+                            summary::finish("ok");
+                            succeeded += 1
+                        }
+                        Err(payload) => {
+                            let message = panic_message(payload);
+                            summary::fail(&message);
+                            // waiting won't get the delegation a retry lacks
+                            if message.contains(delegate::PERMISSION_DENIED) {
+                                denied.push(origins);
+                            } else {
+                                failed.push(origins);
+                            }
+                            if cmd.fail_fast {
+                                break;
+                            }
+                        }
+                    }
+                }
+                pending = failed;
+            }
+            pending.extend(denied);
+            if !pending.is_empty() {
+                println!(
+                    "Failed to snapshot {} dataset(s): {}",
+                    pending.iter().flatten().count(),
+                    pending
+                        .iter()
+                        .flatten()
+                        .map(|x| x.dataset.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                // 2 tells a scheduler that only some datasets need a retry
+                exit(if succeeded == 0 { 1 } else { 2 });
+            }
+        }
+        Subcommand::Send(cmd) if cmd.recursive => send_tree(&cmd, &now, app.wait),
+        Subcommand::Send(cmd) => {
+            if !cmd.exclude.is_empty() {
+                panic!("--exclude only applies with --recursive");
+            }
+            if cmd.jobs != 1 {
+                panic!("--jobs only applies with --recursive");
+            }
+            summary::start(&cmd.name);
+            let outcome = send(
+                &cmd,
+                &cmd.name,
+                &cmd.from,
+                &cmd.to,
+                Instant::now(),
+                &now,
+                app.wait,
+            );
+            // send_error leaves the reason in the summary
+            if summary::failed() {
+                exit(1);
+            }
+            if outcome == SendOutcome::Partial {
+                // like snap, 2 tells a scheduler the job is only partly done
+                exit(2);
+            }
+            summary::finish("ok");
+        }
+        Subcommand::Compare(cmd) => {
+            let origin = parse_remote(&cmd.from);
+            let destination = parse_remote(&cmd.to);
+            compare::compare(&origin, &destination);
+        }
+        Subcommand::Chain(cmd) => chain::chain(
+            &parse_remote(&cmd.location),
+            &cmd.names,
+            &cmd.replicas
+                .iter()
+                .map(|x| parse_remote(x))
+                .collect::<Vec<_>>(),
+            &cmd.format,
+        ),
+        Subcommand::Clone(cmd) => {
+            if cmd.cleanup {
+                let remote = parse_remote(cmd.target.as_deref().unwrap_or(""));
+                clone::cleanup(&remote);
+            } else {
+                let target = cmd.target.expect("missing snapshot to clone");
+                clone::clone(
+                    &parse_remote(&target),
+                    cmd.mountpoint.as_deref(),
+                    &cmd.key.source(),
+                );
+            }
+        }
+        Subcommand::RestoreFile(cmd) => {
+            // a bad time should fail before any listing
+            let at = cmd.at.as_deref().map(restore::parse_at);
+            restore::restore_file(
+                &cmd.source,
+                &cmd.dest,
+                &cmd.key.source(),
+                at.as_ref(),
+                &cmd.replica,
+            );
+        }
+        Subcommand::Watch(_) => progress::watch(),
+        Subcommand::Delegate(cmd) => {
+            if cmd.from.is_none() && cmd.to.is_none() {
+                panic!("nothing to delegate, pass --from and/or --to");
+            }
+            let origin = cmd.from.as_deref().map(parse_remote);
+            let destination = cmd.to.as_deref().map(parse_remote);
+            delegate::delegate(&cmd.user, origin.as_ref(), destination.as_ref(), cmd.apply);
+        }
+        Subcommand::Selftest(_) => selftest::selftest(),
+        Subcommand::Man(cmd) => man::write_pages(&cmd.dir),
+        Subcommand::PruneRemote(cmd) => {
+            let destination = parse_remote(&cmd.to);
+            prune_destination(
+                &destination,
+                &parse_spec(&cmd.keep),
+                &now,
+                cmd.release_holds.as_deref(),
+                cmd.dry_run,
+            );
+        }
+        Subcommand::Gc(cmd) => {
+            // every job's chain would go, a forgotten --name must not do that
+            if cmd.names.is_empty() && !cmd.all_retired {
+                panic!("without --name, gc would remove the sync bookmarks of every job; name the jobs still sending with --name, or pass --all-retired");
+            }
+            if !cmd.names.is_empty() && cmd.all_retired {
+                panic!("--all-retired and --name contradict each other");
+            }
+            gc::gc(
+                &parse_remote(&cmd.location),
+                &cmd.names,
+                cmd.release_holds.as_deref(),
+                cmd.force,
+                cmd.dry_run,
+            )
+        }
+        Subcommand::Audit(cmd) => {
+            let spec = parse_spec(&cmd.keep);
+            let gaps = cmd
+                .location
+                .iter()
+                .map(|x| audit::audit(&parse_remote(x), &spec, &now))
+                .sum::<usize>();
+            if gaps > 0 {
+                println!("Found {} gap(s).", gaps);
+                exit(1);
+            }
+        }
+        Subcommand::DrExport(cmd) => {
+            dr::export(
+                &parse_remote(&cmd.source),
+                &cmd.replicas,
+                &parse_remote(&cmd.output),
+                &now,
+            );
+        }
+        Subcommand::DrPlan(cmd) => dr::plan(&parse_remote(&cmd.input)),
+        Subcommand::Status(cmd) => {
+            let max_age = cmd.max_age.as_deref().map(parse_duration);
+            if cmd.throughput && cmd.format != "text" {
+                panic!("--throughput only goes with --format text");
+            }
+            let failed = status::status(&cmd.location, max_age, &cmd.format, &now);
+            if cmd.throughput {
+                println!();
+                history::print_trends();
+            }
+            if failed > 0 {
+                exit(1);
+            }
+        }
+        Subcommand::Catalog(cmd) => catalog::catalog(&cmd.location, &cmd.format),
+        Subcommand::Bench(cmd) => bench::bench(
+            &parse_remote(cmd.to.as_deref().unwrap_or("")),
+            throttle::parse_bytes(&cmd.size),
+            cmd.zeros,
+            cmd.bwlimit
+                .as_deref()
+                .map(throttle::parse_schedule)
+                .as_ref(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            println!("Done.");
+    fn ssh(target: &str, port: Option<u16>) -> Transport {
+        Transport::SSH(SshHost {
+            target: target.to_string(),
+            port,
+        })
+    }
+
+    #[test]
+    fn local_datasets() {
+        assert_eq!(split_remote("zroot/code"), (Transport::Local, "zroot/code"));
+        assert_eq!(
+            split_remote("zroot/code@2021-06-01T0000"),
+            (Transport::Local, "zroot/code@2021-06-01T0000")
+        );
+        // a slash before the first colon can't be part of a host
+        assert_eq!(split_remote("tank/a:b"), (Transport::Local, "tank/a:b"));
+    }
+
+    #[test]
+    fn scp_style() {
+        assert_eq!(
+            split_remote("rpi4.local:rpool/code"),
+            (ssh("rpi4.local", None), "rpool/code")
+        );
+        assert_eq!(
+            split_remote("backup@rpi4.local:rpool/code@snap"),
+            (ssh("backup@rpi4.local", None), "rpool/code@snap")
+        );
+        assert_eq!(
+            split_remote("host:tank/with:colon"),
+            (ssh("host", None), "tank/with:colon")
+        );
+        assert_eq!(split_remote("host:"), (ssh("host", None), ""));
+    }
+
+    #[test]
+    fn bracketed_ipv6() {
+        assert_eq!(
+            split_remote("[::1]:tank/code"),
+            (ssh("::1", None), "tank/code")
+        );
+        assert_eq!(
+            split_remote("backup@[fe80::1%eth0]:tank"),
+            (ssh("backup@fe80::1%eth0", None), "tank")
+        );
+    }
+
+    #[test]
+    fn ssh_urls() {
+        assert_eq!(
+            split_remote("ssh://backup@host:2222/pool/dataset"),
+            (ssh("backup@host", Some(2222)), "pool/dataset")
+        );
+        assert_eq!(split_remote("ssh://host/pool"), (ssh("host", None), "pool"));
+        assert_eq!(
+            split_remote("ssh://[2001:db8::1]:22/pool/a@snap"),
+            (ssh("2001:db8::1", Some(22)), "pool/a@snap")
+        );
+        assert_eq!(split_remote("ssh://[::1]/pool"), (ssh("::1", None), "pool"));
+    }
+
+    #[test]
+    fn snapshot_names_round_trip() {
+        let time = Utc.ymd(2021, 6, 1).and_hms(13, 45, 0);
+        for (name, format, _) in NAME_FORMATS {
+            let path = format!("zroot/code@{}", time.format(format));
+            assert!(is_normal_snapshot(&path), "{} from {}", path, name);
+            assert_eq!(time_from_name(&path), Some(time), "{}", path);
+            let bookmark = format!("{}-sync-rpi4", path.replace('@', "#"));
+            assert!(sync_bookmark().is_match(&bookmark), "{}", bookmark);
+            assert_eq!(time_from_name(&bookmark), Some(time), "{}", bookmark);
+        }
+        assert!(!is_normal_snapshot("zroot/code@2021-06-01T13450"));
+        assert_eq!(time_from_name("zroot/code@manual"), None);
+    }
+
+    #[test]
+    fn snapshots_of_any_dataset() {
+        for dataset in ["tank/vm/vm-101", "tank/Data2", "pool_1/a.b:c"] {
+            let path = format!("{}@2021-06-01T1345", dataset);
+            assert!(is_normal_snapshot(&path), "{}", path);
+            let labeled = format!("{}-pre-upgrade", path);
+            assert_eq!(snapshot_label(&labeled), Some("pre-upgrade"), "{}", labeled);
+            let bookmark = format!("{}-sync-rpi4", path.replace('@', "#"));
+            assert!(sync_bookmark().is_match(&bookmark), "{}", bookmark);
         }
+        assert!(!is_normal_snapshot("tank/vm@101@2021-06-01T1345"));
+    }
+
+    #[test]
+    fn labeled_snapshots() {
+        let time = Utc.ymd(2021, 6, 1).and_hms(13, 45, 0);
+        for (_, format, _) in NAME_FORMATS {
+            let path = format!("zroot/code@{}-pre-upgrade", time.format(format));
+            assert!(is_normal_snapshot(&path), "{}", path);
+            assert_eq!(snapshot_label(&path), Some("pre-upgrade"), "{}", path);
+            assert_eq!(time_from_name(&path), Some(time), "{}", path);
+            let bookmark = format!("{}-sync-rpi4", path.replace('@', "#"));
+            assert!(sync_bookmark().is_match(&bookmark), "{}", bookmark);
+        }
+        assert_eq!(snapshot_label("zroot/code@2021-06-01T1345"), None);
+
+        let snapshot = |name: &str| Snapshot {
+            path: format!("zroot/code@{}", name),
+            time,
+            guid: name.to_string(),
+        };
+        let spec = parse_spec("2h2l");
+        let plan = find_prunable(
+            &time,
+            &spec,
+            [
+                "2021-06-01T0000-a",
+                "2021-06-01T1345-a",
+                "2021-06-01T1345",
+                "2021-06-01T1346-a",
+            ]
+            .iter()
+            .map(|x| snapshot(x)),
+        );
+        let names = |x: &[Snapshot]| x.iter().map(|x| x.guid.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            names(&plan.keep),
+            ["2021-06-01T1345-a", "2021-06-01T1345", "2021-06-01T1346-a"]
+        );
+        assert_eq!(names(&plan.remove), ["2021-06-01T0000-a"]);
+
+        // labels with a policy of their own
+        let spec = parse_spec("2h2l,a=1l,b=2h");
+        let plan = find_prunable(
+            &time,
+            &spec,
+            [
+                "2021-06-01T1345-a",
+                "2021-06-01T1345-b",
+                "2021-06-01T1346-b",
+                "2021-06-01T1346-a",
+            ]
+            .iter()
+            .map(|x| snapshot(x)),
+        );
+        assert_eq!(
+            names(&plan.keep),
+            ["2021-06-01T1345-b", "2021-06-01T1346-a"]
+        );
+        assert_eq!(
+            names(&plan.remove),
+            ["2021-06-01T1345-a", "2021-06-01T1346-b"]
+        );
+    }
+
+    #[test]
+    fn send_bases_are_kept() {
+        let now = Utc.ymd(2021, 6, 1).and_hms(12, 0, 0);
+        let snapshot = |hour: u32, guid: &str| Snapshot {
+            path: format!("zroot/code@2021-06-01T{:02}30", hour),
+            time: Utc.ymd(2021, 6, 1).and_hms(hour, 30, 0),
+            guid: guid.to_string(),
+        };
+        let snapshots = [snapshot(8, "8"), snapshot(9, "9"), snapshot(11, "11")];
+        let mut plan = find_prunable(&now, &parse_spec("2h"), snapshots);
+        let names = |x: &[Snapshot]| x.iter().map(|x| x.guid.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&plan.remove), ["8", "9"]);
+
+        // a job that last replicated @0830 sends from it next
+        let protected = plan.protect(&["8".to_string(), "10".to_string()]);
+        assert_eq!(names(&protected), ["8"]);
+        assert_eq!(names(&plan.remove), ["9"]);
+        assert_eq!(names(&plan.keep), ["11"]);
+        assert!(plan.protect(&[]).is_empty());
+    }
+
+    #[test]
+    fn remote_commands_are_quoted() {
+        assert_eq!(
+            remote_command("zfs", &["snapshot", "tank/my data@x", "it's"]),
+            "zfs snapshot 'tank/my data@x' 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn destination_properties() {
+        assert_eq!(destination_property("rpi4"), "zbak:destination:rpi4");
+        assert_eq!(
+            destination_property("RPi4 a+b"),
+            "zbak:destination::52:50i4:20a:2bb"
+        );
+    }
+
+    #[test]
+    fn permission_denied() {
+        let remote = parse_remote("backup@nas:tank/backups/laptop");
+        let err = delegate::PermissionDenied::detect(
+            &remote,
+            &["receive", "-s", "-u", "tank/backups/laptop"],
+            "cannot receive new filesystem stream: permission denied\n",
+        )
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "permission denied on backup@nas: `zfs receive -s -u tank/backups/laptop` needs create,mount,receive on tank/backups. Delegate it there with `zfs allow -u backup create,mount,receive tank/backups`, or see zbak delegate."
+        );
+        // ssh failing to log in is not about delegation
+        assert!(delegate::PermissionDenied::detect(
+            &remote,
+            &["snapshot", "tank/backups/laptop@x"],
+            "backup@nas: Permission denied (publickey).",
+        )
+        .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid character ';'")]
+    fn shell_metacharacters_are_rejected() {
+        parse_remote("host:tank/a;reboot");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected ssh://")]
+    fn ssh_url_without_dataset() {
+        split_remote("ssh://host");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid port")]
+    fn ssh_url_with_bad_port() {
+        split_remote("ssh://host:ssh/pool");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected [address]:dataset")]
+    fn unterminated_ipv6() {
+        split_remote("[::1:tank");
     }
 }
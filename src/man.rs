@@ -0,0 +1,159 @@
+use clap::{App, Arg, ArgSettings, IntoApp};
+use std::path::Path;
+
+/// Sections of zbak(1) that clap knows nothing about.
+const EXTRA_SECTIONS: &str = r#".SH KEEP SPECIFICATIONS
+A keep specification such as \fB7d24h4f\fR is a list of counts, each
+followed by a unit:
+.TP
+.B m
+monthly snapshots
+.TP
+.B w
+weekly snapshots
+.TP
+.B d
+daily snapshots
+.TP
+.B h
+hourly snapshots
+.TP
+.B f
+frequent snapshots, one per 15 minutes
+.TP
+.B l
+snapshots of each label given to \fBsnap \-\-label\fR, newest first
+.PP
+Snapshots not needed to fill any of the slots are pruned.
+Labeled snapshots fill no slots; without an \fBl\fR count, all of them are
+kept.
+A label may get a policy of its own after a comma, as in
+\fB7d24h4f,pre\-upgrade=12m,manual=10l\fR: one \fBpre\-upgrade\fR
+snapshot per month for a year, and the last 10 \fBmanual\fR ones.
+.SH REMOTES
+Datasets are given as \fIdataset\fR for the local machine, or as
+[\fIuser\fB@\fR]\fIhost\fB:\fIdataset\fR to reach them over ssh.
+IPv6 addresses are bracketed, as in \fB[::1]:\fIdataset\fR.
+To also give a port, use
+\fBssh://\fR[\fIuser\fB@\fR]\fIhost\fR[\fB:\fIport\fR]\fB/\fIdataset\fR.
+A \fB/\fR before the first \fB:\fR always means a local dataset.
+Snapshots are named \fIdataset\fB@\fIsnapshot\fR.
+.SH ENVIRONMENT
+.TP
+.B ZBAK_SSH_OPTS
+Extra options passed to ssh before zbak's defaults.
+.TP
+.B ZBAK_STATE_DIR
+Where the instance locks, progress files, throughput history and sockets
+of shared ssh connections are kept.
+"#;
+
+/// Escapes text for roff, so that dashes and leading dots survive.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+fn is_positional(arg: &Arg) -> bool {
+    arg.get_long().is_none() && arg.get_short().is_none()
+}
+
+fn option(arg: &Arg) -> String {
+    let mut out = String::from(".TP\n");
+    if is_positional(arg) {
+        out += &format!("\\fI{}\\fR\n", escape(&arg.get_name().to_uppercase()));
+    } else {
+        let mut names = Vec::new();
+        if let Some(short) = arg.get_short() {
+            names.push(format!("\\fB\\-{}\\fR", short));
+        }
+        if let Some(long) = arg.get_long() {
+            names.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+        }
+        out += &names.join(", ");
+        if arg.is_set(ArgSettings::TakesValue) {
+            out += &format!(" \\fI{}\\fR", escape(&arg.get_name().to_uppercase()));
+        }
+        out.push('\n');
+    }
+    if let Some(about) = arg.get_about() {
+        out += &escape(about);
+        out.push('\n');
+    }
+    out
+}
+
+fn page(app: &App, name: &str, invocation: &str, synopsis: &str, extra: &str) -> String {
+    let mut out = format!(".TH {} 1\n", name.to_uppercase());
+    out += &format!(
+        ".SH NAME\n{} \\- {}\n",
+        name,
+        escape(app.get_about().unwrap_or(""))
+    );
+
+    out += &format!(".SH SYNOPSIS\n.B {}\n{}", invocation, synopsis);
+    for arg in app.get_arguments().filter(|x| is_positional(x)) {
+        out += &format!(" \\fI{}\\fR", escape(&arg.get_name().to_uppercase()));
+    }
+    out.push('\n');
+
+    let args = app.get_arguments().collect::<Vec<_>>();
+    if !args.is_empty() {
+        out += ".SH OPTIONS\n";
+        for arg in args {
+            out += &option(arg);
+        }
+    }
+    out += extra;
+    out
+}
+
+/// Writes zbak(1) and one page per subcommand, e.g. zbak-snap(1), to `dir`.
+pub fn write_pages(dir: &str) {
+    let app = <crate::App as IntoApp>::into_app();
+
+    let mut commands = String::from(".SH COMMANDS\n");
+    for sub in app.get_subcommands() {
+        commands += &format!(
+            ".TP\n\\fBzbak-{}\\fR(1)\n{}\n",
+            sub.get_name(),
+            escape(sub.get_about().unwrap_or(""))
+        );
+    }
+    let mut pages = vec![(
+        "zbak".to_string(),
+        page(
+            &app,
+            "zbak",
+            "zbak",
+            "[\\fIOPTIONS\\fR] \\fICOMMAND\\fR",
+            &(commands + EXTRA_SECTIONS),
+        ),
+    )];
+
+    for sub in app.get_subcommands() {
+        let name = format!("zbak-{}", sub.get_name());
+        let see_also = ".SH SEE ALSO\n\\fBzbak\\fR(1)\n";
+        pages.push((
+            name.clone(),
+            page(
+                sub,
+                &name,
+                &format!("zbak {}", sub.get_name()),
+                "[\\fIOPTIONS\\fR]",
+                see_also,
+            ),
+        ));
+    }
+
+    for (name, contents) in pages {
+        let path = Path::new(dir).join(format!("{}.1", name));
+        println!("Writing {}.", path.display());
+        std::fs::write(&path, contents)
+            .unwrap_or_else(|e| panic!("cannot write {}: {}", path.display(), e));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{command_output, trace, Remote, Transport};
+use std::sync::{Mutex, OnceLock};
+
+/// The operating systems zbak knows the differences of. They share zfs's
+/// command line, but not the tools around it, e.g. ionice is Linux only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    Linux,
+    FreeBsd,
+    Illumos,
+    Other,
+}
+
+impl Platform {
+    /// From `uname -s`, or a `--platform` hint.
+    fn parse(name: &str) -> Platform {
+        match name.trim().to_lowercase().as_str() {
+            "linux" => Platform::Linux,
+            "freebsd" => Platform::FreeBsd,
+            // illumos distributions still call themselves SunOS
+            "sunos" | "illumos" | "solaris" => Platform::Illumos,
+            _ => Platform::Other,
+        }
+    }
+}
+
+/// Hosts from `--platform`, which are never asked.
+static HINTS: OnceLock<Vec<(String, Platform)>> = OnceLock::new();
+/// Hosts asked so far, so that each is asked once per run.
+static DETECTED: Mutex<Vec<(String, Platform)>> = Mutex::new(Vec::new());
+
+/// Sets the `--platform` hints, e.g. `nas.local=freebsd`.
+pub fn set_hints(hints: &[String]) {
+    let hints = hints
+        .iter()
+        .map(|hint| match hint.split_once('=') {
+            Some((host, name)) if Platform::parse(name) != Platform::Other => {
+                (host.to_string(), Platform::parse(name))
+            }
+            _ => panic!(
+                "invalid --platform {}, expected HOST=linux, HOST=freebsd or HOST=illumos",
+                hint
+            ),
+        })
+        .collect();
+    HINTS.set(hints).ok();
+}
+
+/// The operating system of `remote`'s host, from a hint or `uname -s`. A
+/// host that can't tell, e.g. one whose ssh key only allows zfs, is Other.
+pub fn of(remote: &Remote) -> Platform {
+    let host = match &remote.transport {
+        Transport::Local => return Platform::parse(std::env::consts::OS),
+        Transport::SSH(host) => &host.target,
+    };
+    let hint = HINTS
+        .get()
+        .and_then(|x| x.iter().find(|x| &x.0 == host).map(|x| x.1));
+    if let Some(platform) = hint {
+        return platform;
+    }
+    if let Some(x) = DETECTED.lock().unwrap().iter().find(|x| &x.0 == host) {
+        return x.1;
+    }
+
+    let mut cmd = remote.command("uname", &["-s"]);
+    trace(&[&cmd]);
+    let platform = match command_output(&mut cmd) {
+        Ok(out) if out.status.success() => Platform::parse(&String::from_utf8_lossy(&out.stdout)),
+        _ => Platform::Other,
+    };
+    DETECTED.lock().unwrap().push((host.clone(), platform));
+    platform
+}
+
+/// Hosts whose zfs was asked whether it has `zfs list -j`.
+static JSON_LIST: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
+
+/// Whether a `zfs version` line such as `zfs-2.3.0-1` is OpenZFS 2.3 or
+/// newer, which added JSON output.
+fn has_json_list(version: &str) -> bool {
+    let numbers = version
+        .lines()
+        .next()
+        .and_then(|x| x.strip_prefix("zfs-"))
+        .unwrap_or("")
+        .split(['.', '-'])
+        .take(2)
+        .map(|x| x.parse::<u32>().unwrap_or(0))
+        .collect::<Vec<_>>();
+    matches!(numbers[..], [major, minor] if (major, minor) >= (2, 3))
+}
+
+/// Whether `remote`'s zfs can list as JSON, asked once per host.
+pub fn zfs_json(remote: &Remote) -> bool {
+    let host = match &remote.transport {
+        Transport::Local => String::new(),
+        Transport::SSH(host) => host.target.clone(),
+    };
+    if let Some(x) = JSON_LIST.lock().unwrap().iter().find(|x| x.0 == host) {
+        return x.1;
+    }
+    // older zfs and other implementations have no version subcommand
+    let json = remote
+        .exec(&["version"])
+        .is_ok_and(|out| has_json_list(&out));
+    JSON_LIST.lock().unwrap().push((host, json));
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_names() {
+        assert_eq!(Platform::parse("Linux\n"), Platform::Linux);
+        assert_eq!(Platform::parse("FreeBSD\n"), Platform::FreeBsd);
+        assert_eq!(Platform::parse("SunOS\n"), Platform::Illumos);
+        assert_eq!(Platform::parse("illumos"), Platform::Illumos);
+        assert_eq!(Platform::parse("Darwin\n"), Platform::Other);
+    }
+
+    #[test]
+    fn zfs_versions() {
+        assert!(has_json_list("zfs-2.3.0-1\nzfs-kmod-2.3.0-1\n"));
+        assert!(has_json_list("zfs-2.10.1-FreeBSD_g1234\n"));
+        assert!(!has_json_list("zfs-2.2.6-1\nzfs-kmod-2.2.6-1\n"));
+        assert!(!has_json_list("zfs-0.8.3-1ubuntu12\n"));
+        assert!(!has_json_list(""));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{format_bytes, state::state_dir};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::{io::FromRawFd, net::UnixStream},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Numbers the progress files of this run, as parallel sends each have one.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+const PREFIX: &str = "progress.";
+
+fn progress_path() -> PathBuf {
+    let n = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+    state_dir().join(format!("{}{}.{}", PREFIX, std::process::id(), n))
+}
+
+/// State of one job of the current run, mirrored to a file of its own in
+/// the state directory so that `zbak watch` can follow it from another
+/// terminal.
+pub struct Progress {
+    path: PathBuf,
+    job: String,
+    phase: String,
+    snapshot: String,
+    bytes: u64,
+    estimate: Option<u64>,
+    started: Instant,
+    last_write: Instant,
+    events: Option<Box<dyn Write>>,
+}
+
+/// Opens the destination of `--progress-json`: `fd:N` for an inherited file
+/// descriptor, `unix:PATH` for a listening socket, or a file to append to.
+pub fn open_events(target: &str) -> Box<dyn Write> {
+    if let Some(fd) = target.strip_prefix("fd:") {
+        let fd = fd
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid file descriptor {}", fd));
+        // a duplicate, so that the fd stays open for the next send of a
+        // recursive run
+        let inherited = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        Box::new(
+            inherited
+                .try_clone()
+                .unwrap_or_else(|e| panic!("cannot use file descriptor {}: {}", fd, e)),
+        )
+    } else if let Some(path) = target.strip_prefix("unix:") {
+        Box::new(
+            UnixStream::connect(path)
+                .unwrap_or_else(|e| panic!("cannot connect to {}: {}", path, e)),
+        )
+    } else {
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(target)
+                .unwrap_or_else(|e| panic!("cannot open {}: {}", target, e)),
+        )
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Progress {
+    /// `events`, if given, receives a JSON object per line for every change
+    /// in progress.
+    pub fn new(job: &str, events: Option<Box<dyn Write>>) -> Progress {
+        let mut progress = Progress {
+            path: progress_path(),
+            job: job.to_string(),
+            phase: "starting".to_string(),
+            snapshot: String::new(),
+            bytes: 0,
+            estimate: None,
+            started: Instant::now(),
+            last_write: Instant::now(),
+            events,
+        };
+        progress.write();
+        progress
+    }
+
+    pub fn set_phase(&mut self, phase: &str) {
+        self.phase = phase.to_string();
+        self.write();
+        self.emit("phase");
+    }
+
+    pub fn start_transfer(&mut self, snapshot: &str, estimate: Option<u64>) {
+        self.phase = "sending".to_string();
+        self.snapshot = snapshot.to_string();
+        self.bytes = 0;
+        self.estimate = estimate;
+        self.started = Instant::now();
+        self.write();
+        self.emit("send_start");
+    }
+
+    pub fn finish_transfer(&mut self) {
+        self.emit("send_done");
+    }
+
+    /// Called for every chunk relayed. The file is rewritten at most once
+    /// per second.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if self.last_write.elapsed() >= Duration::from_secs(1) {
+            self.write();
+            self.emit("send_progress");
+        }
+    }
+
+    fn emit(&mut self, event: &str) {
+        let events = match &mut self.events {
+            Some(x) => x,
+            None => return,
+        };
+        let line = format!(
+            "{{\"event\":{},\"job\":{},\"phase\":{},\"snapshot\":{},\"bytes\":{},\"estimate\":{},\"elapsed\":{:.3}}}\n",
+            json_string(event),
+            json_string(&self.job),
+            json_string(&self.phase),
+            json_string(&self.snapshot),
+            self.bytes,
+            self.estimate.map_or("null".to_string(), |x| x.to_string()),
+            self.started.elapsed().as_secs_f64(),
+        );
+        // a listener going away must not abort the backup itself
+        if let Err(e) = events
+            .write_all(line.as_bytes())
+            .and_then(|_| events.flush())
+        {
+            eprintln!("Warning: stopping progress events: {}", e);
+            self.events = None;
+        }
+    }
+
+    fn write(&mut self) {
+        self.last_write = Instant::now();
+        let mut contents = format!(
+            "pid={}\njob={}\nphase={}\nsnapshot={}\nbytes={}\nelapsed={}\n",
+            std::process::id(),
+            self.job,
+            self.phase,
+            self.snapshot,
+            self.bytes,
+            self.started.elapsed().as_secs_f64(),
+        );
+        if let Some(estimate) = self.estimate {
+            contents += &format!("estimate={}\n", estimate);
+        }
+
+        // write then rename so that readers never see a partial file
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = std::fs::File::create(&tmp).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        std::fs::rename(&tmp, &self.path).unwrap();
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.emit("finished");
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// The progress of every job running, oldest first.
+fn read_progress() -> Vec<HashMap<String, String>> {
+    let mut paths = match std::fs::read_dir(state_dir()) {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .filter(|x| x.starts_with(PREFIX) && !x.ends_with(".tmp"))
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    // by pid and number, which sorting the names as text would mix up
+    paths.sort_by_key(|name| {
+        name[PREFIX.len()..]
+            .split('.')
+            .map(|x| x.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    });
+    paths
+        .iter()
+        .filter_map(|name| {
+            let path = state_dir().join(name);
+            // a job may finish between listing and reading
+            let progress = std::fs::read_to_string(&path)
+                .ok()?
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>();
+            // left behind by a run that was killed, e.g. by the OOM killer
+            let pid = progress.get("pid").and_then(|x| x.parse().ok());
+            if pid.is_some_and(|pid| !is_running(pid)) {
+                std::fs::remove_file(&path).ok();
+                return None;
+            }
+            Some(progress)
+        })
+        // never a half-read file, but one written by another version
+        .filter(|x| ["job", "phase"].iter().all(|key| x.contains_key(*key)))
+        .collect()
+}
+
+/// Whether the process `pid` still exists, if perhaps as another user.
+fn is_running(pid: libc::pid_t) -> bool {
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn describe(progress: &HashMap<String, String>) -> String {
+    let mut line = format!("{} ({})", progress["job"], progress["phase"]);
+    if progress["phase"] == "sending" {
+        let bytes = progress["bytes"].parse::<u64>().unwrap();
+        let elapsed = progress["elapsed"].parse::<f64>().unwrap().max(0.001);
+        let rate = bytes as f64 / elapsed;
+        line += &format!(
+            ": {} {}, {}/s",
+            progress["snapshot"],
+            format_bytes(bytes),
+            format_bytes(rate as u64)
+        );
+        if let Some(estimate) = progress.get("estimate") {
+            let estimate = estimate.parse::<u64>().unwrap();
+            let remaining = estimate.saturating_sub(bytes) as f64;
+            line += &format!(
+                " of {} ({:.0}%)",
+                format_bytes(estimate),
+                100.0 * bytes as f64 / estimate.max(1) as f64,
+            );
+            if bytes > 0 {
+                line += &format!(", ETA {}", format_duration(remaining / rate));
+            }
+        }
+    }
+    line
+}
+
+/// Follows the progress files of a running zbak until the run finishes, a
+/// line per job.
+pub fn watch() {
+    let mut shown = 0;
+    loop {
+        let progress = read_progress();
+        if progress.is_empty() {
+            if shown > 0 {
+                println!("\nDone.");
+            } else {
+                println!("No zbak run in progress.");
+            }
+            return;
+        }
+
+        // back to the first line shown, clearing those no longer needed
+        if shown > 1 {
+            print!("\x1b[{}A", shown - 1);
+        }
+        let lines = progress.len().max(shown);
+        for i in 0..lines {
+            let line = progress.get(i).map_or(String::new(), describe);
+            // pad to overwrite a previous, longer line
+            print!("\r{:80}{}", line, if i + 1 < lines { "\n" } else { "" });
+        }
+        shown = lines;
+        std::io::stdout().flush().unwrap();
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Prints the current progress to stderr whenever SIGUSR1 is received, for
+/// checking on a run that appears hung.
+pub fn spawn_status_reporter() {
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_millis(200));
+        if crate::signal::take_status_request() {
+            let progress = read_progress();
+            if progress.is_empty() {
+                eprintln!("zbak: idle");
+            }
+            for progress in progress {
+                eprintln!("zbak: {}", describe(&progress));
+            }
+        }
+    });
+}
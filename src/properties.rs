@@ -0,0 +1,41 @@
+use crate::Remote;
+
+fn values(remote: &Remote, properties: &str) -> Vec<(String, String)> {
+    remote
+        .exec(&[
+            "get",
+            "-H",
+            "-o",
+            "property,value",
+            properties,
+            &remote.dataset,
+        ])
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (property, value) = line.split_once('\t').unwrap();
+            (property.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Copies `properties` (comma-separated, as for `zfs get`) from the source
+/// to the replica where they differ. Incremental streams don't carry
+/// property changes made after the first sync, so replicas drift otherwise.
+pub fn sync_properties(origin: &Remote, destination: &Remote, properties: &str) {
+    let current = values(destination, properties);
+    for (property, value) in values(origin, properties) {
+        // unset user properties read as "-"
+        if value == "-" || current.contains(&(property.clone(), value.clone())) {
+            continue;
+        }
+        println!("Setting {}={} on {}.", property, value, destination.dataset);
+        destination
+            .exec(&[
+                "set",
+                &format!("{}={}", property, value),
+                &destination.dataset,
+            ])
+            .unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! Records zfs and ssh interactions to a fixture file, or replays them from
+//! one without touching a pool. Set `ZBAK_RECORD=<file>` to record a run and
+//! `ZBAK_REPLAY=<file>` to replay it. Only built with the `replay` feature.
+//!
+//! A fixture starts with the time of the recorded run, followed by one entry
+//! per command:
+//!
+//! ```text
+//! now 2021-06-01T00:00:00+00:00
+//! $ zfs get -H -o value keystatus zroot/code
+//! status 0
+//! > available
+//! $ zfs destroy zroot/code@2021-05-01T0000
+//! status 1
+//! ! cannot destroy snapshot zroot/code@2021-05-01T0000: dataset is busy
+//! ```
+//!
+//! Streams are not recorded, a replayed transfer only reproduces the exit
+//! status and stderr of the send/recv pipeline.
+//!
+//! `cargo test --features replay` replays the fixtures in tests/fixtures.
+
+use crate::{command_line, CommandError, TransferError};
+use chrono::{DateTime, Utc};
+use std::{
+    fs,
+    io::Write,
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus, Output},
+    sync::{Mutex, OnceLock},
+};
+
+struct Interaction {
+    command: String,
+    status: i32,
+    stdout: String,
+    stderr: String,
+}
+
+enum Mode {
+    Record(fs::File),
+    /// Interactions not replayed yet, in recorded order
+    Replay(Vec<Interaction>),
+}
+
+struct Fixture {
+    now: DateTime<Utc>,
+    mode: Mutex<Mode>,
+}
+
+static FIXTURE: OnceLock<Option<Fixture>> = OnceLock::new();
+
+fn parse(path: &str, contents: &str) -> Fixture {
+    let mut lines = contents.lines();
+    let now = lines
+        .next()
+        .and_then(|x| x.strip_prefix("now "))
+        .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+        .unwrap_or_else(|| panic!("{} does not start with the time of the run", path))
+        .with_timezone(&Utc);
+
+    let mut interactions: Vec<Interaction> = Vec::new();
+    for line in lines {
+        if let Some(command) = line.strip_prefix("$ ") {
+            interactions.push(Interaction {
+                command: command.to_string(),
+                status: 0,
+                stdout: String::new(),
+                stderr: String::new(),
+            });
+            continue;
+        }
+        let current = match interactions.last_mut() {
+            Some(x) => x,
+            None => panic!("{}: output before the first command: {}", path, line),
+        };
+        if let Some(status) = line.strip_prefix("status ") {
+            current.status = status.parse().unwrap();
+        } else if let Some(out) = line.strip_prefix("> ") {
+            current.stdout += out;
+            current.stdout.push('\n');
+        } else if let Some(err) = line.strip_prefix("! ") {
+            current.stderr += err;
+            current.stderr.push('\n');
+        } else if !line.is_empty() {
+            panic!("{}: unexpected line: {}", path, line);
+        }
+    }
+
+    Fixture {
+        now,
+        mode: Mutex::new(Mode::Replay(interactions)),
+    }
+}
+
+fn fixture() -> Option<&'static Fixture> {
+    FIXTURE
+        .get_or_init(|| {
+            if let Ok(path) = std::env::var("ZBAK_REPLAY") {
+                let contents = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+                Some(parse(&path, &contents))
+            } else if let Ok(path) = std::env::var("ZBAK_RECORD") {
+                let now = Utc::now();
+                let mut file = fs::File::create(&path)
+                    .unwrap_or_else(|e| panic!("cannot create {}: {}", path, e));
+                writeln!(file, "now {}", now.to_rfc3339()).unwrap();
+                Some(Fixture {
+                    now,
+                    mode: Mutex::new(Mode::Record(file)),
+                })
+            } else {
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// The time of the run, taken from the fixture when replaying.
+pub fn now() -> DateTime<Utc> {
+    match fixture() {
+        Some(fixture) => fixture.now,
+        None => Utc::now(),
+    }
+}
+
+/// Returns the recorded output of `command` when replaying. Commands are
+/// matched by their command line, the earliest unused recording first.
+pub fn replayed(command: &Command) -> Option<Output> {
+    let command = command_line(command);
+    let mut mode = fixture()?.mode.lock().unwrap();
+    let interactions = match &mut *mode {
+        Mode::Replay(x) => x,
+        Mode::Record(_) => return None,
+    };
+    let idx = match interactions.iter().position(|x| x.command == command) {
+        Some(idx) => idx,
+        None => panic!("no recorded output for {}", command),
+    };
+    let found = interactions.remove(idx);
+    Some(Output {
+        status: ExitStatus::from_raw(found.status << 8),
+        stdout: found.stdout.into_bytes(),
+        stderr: found.stderr.into_bytes(),
+    })
+}
+
+/// Appends the output of `command` to the fixture when recording.
+pub fn record(command: &Command, status: ExitStatus, stdout: &[u8], stderr: &[u8]) {
+    let fixture = match fixture() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut mode = fixture.mode.lock().unwrap();
+    let file = match &mut *mode {
+        Mode::Record(file) => file,
+        Mode::Replay(_) => return,
+    };
+
+    let mut entry = format!(
+        "$ {}\nstatus {}\n",
+        command_line(command),
+        status.code().unwrap_or(-1)
+    );
+    for line in String::from_utf8_lossy(stdout).lines() {
+        entry += &format!("> {}\n", line);
+    }
+    for line in String::from_utf8_lossy(stderr).lines() {
+        entry += &format!("! {}\n", line);
+    }
+    file.write_all(entry.as_bytes()).unwrap();
+}
+
+/// Replays a send/recv pipeline from the recorded exit status and stderr of
+/// both sides, checking the receiving side first like `transfer` does.
+pub fn replayed_transfer(
+    producer: &Command,
+    consumer: &Command,
+) -> Option<Result<u64, TransferError>> {
+    for cmd in [consumer, producer] {
+        let out = replayed(cmd)?;
+        if !out.status.success() {
+            return Some(Err(TransferError::Failed(CommandError::new(
+                cmd,
+                out.status,
+                &out.stderr,
+            ))));
+        }
+    }
+    Some(Ok(0))
+}
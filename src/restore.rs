@@ -0,0 +1,243 @@
+use crate::{
+    clone, encryption, exit, panic_message, parse_remote, split_remote, Snapshot, Transport,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Splits `[host:]pool/ds@snap:path` into the snapshot and the path within
+/// it. The host separator comes before the `@`, the path separator after.
+fn split_spec(spec: &str) -> (&str, &str) {
+    let (transport, rest) = split_remote(spec);
+    // `pool@snap:path` reads like `user@host:dataset`, but what follows the
+    // colon then lacks a `@snap:path` of its own
+    let start = match transport {
+        Transport::SSH(_) if !rest.find('@').is_some_and(|at| rest[at..].contains(':')) => 0,
+        _ => spec.len() - rest.len(),
+    };
+    let at = start
+        + spec[start..]
+            .find('@')
+            .unwrap_or_else(|| panic!("expected <dataset>@<snapshot>:<path>, got {}", spec));
+    match spec[at..].find(':') {
+        Some(idx) => (&spec[..at + idx], &spec[at + idx + 1..]),
+        None => panic!("expected <dataset>@<snapshot>:<path>, got {}", spec),
+    }
+}
+
+/// Reads a time such as `2024-04-01 09:00` in local time, as --window
+/// does.
+pub fn parse_at(input: &str) -> DateTime<Utc> {
+    let time = ["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(input, format).ok())
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                .ok()
+                .and_then(|x| x.and_hms_opt(0, 0, 0))
+        })
+        .unwrap_or_else(|| panic!("invalid time {}, expected e.g. 2024-04-01 09:00", input));
+    match chrono::Local.from_local_datetime(&time).earliest() {
+        Some(x) => x.with_timezone(&Utc),
+        None => panic!("{} does not exist in the local time zone", input),
+    }
+}
+
+/// The newest snapshot taken at or before `at` in any of `listed`, with
+/// the index of its list. Earlier lists win ties, so the dataset itself is
+/// preferred over a replica holding the same snapshot.
+fn newest_at<'a>(listed: &'a [Vec<Snapshot>], at: &DateTime<Utc>) -> Option<(usize, &'a Snapshot)> {
+    let mut best: Option<(usize, &Snapshot)> = None;
+    for (i, snapshots) in listed.iter().enumerate() {
+        for snapshot in snapshots.iter().filter(|x| x.time <= *at) {
+            if best.is_none_or(|x| snapshot.time > x.1.time) {
+                best = Some((i, snapshot));
+            }
+        }
+    }
+    best
+}
+
+/// Turns `[host:]pool/ds@:path` into the newest snapshot at or before `at`
+/// of the dataset or of one of its `replicas`, whichever has it.
+fn snapshot_at(location: &str, replicas: &[String], at: &DateTime<Utc>) -> String {
+    let locations = std::iter::once(location)
+        .chain(replicas.iter().map(|x| x.as_str()))
+        .collect::<Vec<_>>();
+    let listed = locations
+        .iter()
+        .map(|location| {
+            // a replica that is down leaves the others to choose from
+            std::panic::catch_unwind(|| parse_remote(location).list_snapshots()).unwrap_or_else(
+                |payload| {
+                    println!(
+                        "Warning: cannot list snapshots of {}: {}",
+                        location,
+                        panic_message(payload)
+                    );
+                    Vec::new()
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    match newest_at(&listed, at) {
+        Some((i, snapshot)) => {
+            let name = snapshot.path.split_once('@').unwrap().1;
+            println!(
+                "Using {}@{}, taken {}.",
+                locations[i],
+                name,
+                snapshot.time.format("%Y-%m-%d %H:%M:%S UTC")
+            );
+            format!("{}@{}", locations[i], name)
+        }
+        None => panic!(
+            "no snapshot of {} was taken at or before {}",
+            locations.join(" or "),
+            at.format("%Y-%m-%d %H:%M:%S UTC")
+        ),
+    }
+}
+
+/// Copies a file or directory out of a snapshot into the local directory
+/// `dest`, cloning the snapshot first if its dataset isn't mounted. With
+/// `at`, the snapshot is left out of `spec`, as in `pool/ds@:path`, and the
+/// newest one at or before then is used, from the dataset or `replicas`.
+pub fn restore_file(
+    spec: &str,
+    dest: &str,
+    key: &encryption::KeySource,
+    at: Option<&DateTime<Utc>>,
+    replicas: &[String],
+) {
+    let (snapshot, path) = split_spec(spec);
+    // checked before cloning, which would otherwise be left behind
+    let path = Path::new(path.trim_start_matches('/'));
+    let name = match path.file_name() {
+        Some(name) => name.to_str().unwrap(),
+        None => panic!(
+            "nothing to restore, expected a file or directory after the colon in {}",
+            spec
+        ),
+    };
+    let parent = path.parent().unwrap();
+
+    let chosen;
+    let snapshot = match (at, snapshot.strip_suffix('@')) {
+        (Some(at), Some(location)) => {
+            chosen = snapshot_at(location, replicas, at);
+            &chosen
+        }
+        (Some(_), None) => panic!(
+            "--at picks the snapshot, expected <dataset>@:<path>, got {}",
+            spec
+        ),
+        (None, Some(_)) => panic!("expected a snapshot name, or --at to pick one, in {}", spec),
+        (None, None) if !replicas.is_empty() => panic!("--replica only applies with --at"),
+        (None, None) => snapshot,
+    };
+    let remote = parse_remote(snapshot);
+    let (dataset, snap) = remote.dataset.split_once('@').unwrap();
+    if remote.is_volume(dataset) {
+        panic!(
+            "{} is a volume, use zbak clone to get a block device to restore from",
+            dataset
+        );
+    }
+
+    let props = remote
+        .exec(&["get", "-H", "-o", "value", "mounted,mountpoint", dataset])
+        .unwrap();
+    let props = props.lines().collect::<Vec<_>>();
+
+    let (root, clone) = if props[0] == "yes" && props[1].starts_with('/') {
+        (format!("{}/.zfs/snapshot/{}", props[1], snap), None)
+    } else {
+        let (name, mountpoint) = clone::clone(&remote, None, key);
+        (mountpoint, Some(name))
+    };
+
+    let copied = std::panic::catch_unwind(|| {
+        let parent = Path::new(&root).join(parent);
+        println!("Restoring {} into {}.", path.display(), dest);
+
+        let mut producer_cmd =
+            remote.command("tar", &["-C", parent.to_str().unwrap(), "-cf", "-", name]);
+        let mut consumer_cmd = Command::new("tar");
+        consumer_cmd.args(["-C", dest, "-xf", "-"]);
+        crate::trace(&[&producer_cmd, &consumer_cmd]);
+
+        let mut producer = producer_cmd.stdout(Stdio::piped()).spawn().unwrap();
+        let consumer = consumer_cmd
+            .stdin(producer.stdout.take().unwrap())
+            .status()
+            .unwrap();
+        let producer = producer.wait().unwrap();
+        producer.success() && consumer.success()
+    });
+
+    // whatever happened, the clone was only needed for copying
+    if let Some(clone) = clone {
+        println!("Destroying clone {}.", clone);
+        if let Err(e) = remote.exec(&["destroy", &clone]) {
+            println!("Warning: cannot destroy clone {}: {}", clone, e);
+        }
+    }
+
+    match copied {
+        Ok(true) => {}
+        Ok(false) => {
+            println!("Error: restore failed.");
+            exit(1);
+        }
+        Err(payload) => std::panic::resume_unwind(payload),
+    }
+    println!("Done.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newest_at_or_before() {
+        let snapshot = |path: &str, hour| Snapshot {
+            path: path.to_string(),
+            time: Utc.ymd(2024, 4, 1).and_hms(hour, 0, 0),
+            guid: String::new(),
+        };
+        let listed = vec![
+            vec![snapshot("tank/a@08", 8), snapshot("tank/a@10", 10)],
+            // the replica kept one that was pruned locally
+            vec![snapshot("tank/a@08", 8), snapshot("tank/a@09", 9)],
+        ];
+        let at = |hour| Utc.ymd(2024, 4, 1).and_hms(hour, 30, 0);
+        assert_eq!(
+            newest_at(&listed, &at(9)).map(|x| (x.0, x.1.path.as_str())),
+            Some((1, "tank/a@09"))
+        );
+        assert_eq!(newest_at(&listed, &at(8)).map(|x| x.0), Some(0));
+        assert_eq!(newest_at(&listed, &at(7)), None);
+    }
+
+    #[test]
+    fn splits_snapshot_from_path() {
+        assert_eq!(split_spec("pool/ds@snap:a/b"), ("pool/ds@snap", "a/b"));
+        assert_eq!(split_spec("pool@snap:a@b"), ("pool@snap", "a@b"));
+        assert_eq!(
+            split_spec("host:pool/ds@snap:a"),
+            ("host:pool/ds@snap", "a")
+        );
+        assert_eq!(
+            split_spec("backup@host:pool/ds@snap:a"),
+            ("backup@host:pool/ds@snap", "a")
+        );
+        assert_eq!(split_spec("host:pool/ds@:a"), ("host:pool/ds@", "a"));
+        assert_eq!(
+            split_spec("ssh://backup@host:2222/pool@snap:a"),
+            ("ssh://backup@host:2222/pool@snap", "a")
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::Remote;
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Translates a dataset glob into an anchored regex. `*` and `?` stay
+/// within one level of the hierarchy, `**` crosses levels and `[...]`
+/// is a character class.
+pub fn glob_regex(pattern: &str) -> regex::Regex {
+    let mut out = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                out.push_str(".*");
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => {
+                out.push('[');
+                // a glob negates a class with `!`, a regex with `^`; like
+                // `?`, a negated class doesn't match the `/` between levels
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    out.push_str("^/");
+                }
+                for ch in chars.by_ref() {
+                    if ch == ']' {
+                        break;
+                    }
+                    if ch == '\\' {
+                        out.push('\\');
+                    }
+                    out.push(ch);
+                }
+                out.push(']');
+            }
+            _ => out.push_str(&regex::escape(&ch.to_string())),
+        }
+    }
+    out.push('$');
+    regex::Regex::new(&out).unwrap_or_else(|_| panic!("invalid dataset glob {}", pattern))
+}
+
+/// Whether `name`, or a dataset it is below, matches one of `exclude`.
+/// Only levels below `root` count, so that `**/tmp` skips children of a
+/// tree rather than the tree itself.
+fn is_excluded(name: &str, root: &str, exclude: &[regex::Regex]) -> bool {
+    name.match_indices('/')
+        .map(|(i, _)| &name[..i])
+        .chain(std::iter::once(name))
+        .filter(|x| x.len() > root.len())
+        .any(|x| exclude.iter().any(|re| re.is_match(x)))
+}
+
+/// Expands a glob in `remote.dataset` against the datasets that currently
+/// exist on the remote, dropping those matching any of `exclude` and what
+/// is below them. A plain dataset name is returned as is.
+pub fn expand(remote: &Remote, exclude: &[String]) -> Vec<Remote> {
+    if !is_glob(&remote.dataset) {
+        return vec![remote.with_dataset(&remote.dataset)];
+    }
+
+    let include = glob_regex(&remote.dataset);
+    let exclude = exclude.iter().map(|x| glob_regex(x)).collect::<Vec<_>>();
+    remote
+        .exec(&["list", "-H", "-o", "name", "-t", "filesystem,volume"])
+        .unwrap()
+        .lines()
+        .filter(|name| include.is_match(name))
+        .filter(|name| !is_excluded(name, "", &exclude))
+        .map(|name| remote.with_dataset(name))
+        .collect()
+}
+
+/// `remote.dataset` and the datasets below it, parents first, leaving out
+/// those matching any of `exclude` and what is below them.
+pub fn tree(remote: &Remote, exclude: &[String]) -> Vec<Remote> {
+    let exclude = exclude.iter().map(|x| glob_regex(x)).collect::<Vec<_>>();
+    remote
+        .exec(&[
+            "list",
+            "-H",
+            "-o",
+            "name",
+            "-t",
+            "filesystem,volume",
+            "-r",
+            &remote.dataset,
+        ])
+        .unwrap()
+        .lines()
+        .filter(|name| !is_excluded(name, &remote.dataset, &exclude))
+        .map(|name| remote.with_dataset(name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let class = glob_regex("tank/vm[0-9]");
+        assert!(class.is_match("tank/vm1"));
+        assert!(!class.is_match("tank/vmx"));
+        let negated = glob_regex("tank/[!a]*");
+        assert!(negated.is_match("tank/bob"));
+        assert!(!negated.is_match("tank/ann"));
+        assert!(!glob_regex("tank[!a]b").is_match("tank/b"));
+        assert!(glob_regex("tank/**").is_match("tank/a/b"));
+        assert!(!glob_regex("tank/*").is_match("tank/a/b"));
+    }
+
+    #[test]
+    fn excluded_subtrees() {
+        let exclude = [glob_regex("tank/home/*/cache"), glob_regex("**/tmp")];
+        assert!(is_excluded("tank/home/ann/cache", "tank/home", &exclude));
+        assert!(is_excluded(
+            "tank/home/ann/cache/npm",
+            "tank/home",
+            &exclude
+        ));
+        assert!(is_excluded("tank/home/tmp", "tank/home", &exclude));
+        assert!(!is_excluded("tank/home/ann/src", "tank/home", &exclude));
+        // the tree asked for is sent even if it matches
+        assert!(!is_excluded("tank/tmp", "tank/tmp", &exclude));
+        assert!(is_excluded("tank/tmp/x", "", &exclude));
+    }
+}
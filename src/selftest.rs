@@ -0,0 +1,190 @@
+use crate::{parse_remote, Remote};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::Ordering,
+};
+
+const SOURCE_POOL: &str = "zbak-selftest-src";
+const DESTINATION_POOL: &str = "zbak-selftest-dst";
+
+/// Destroys the pools and their backing files, also when a step panics.
+struct Pools {
+    dir: PathBuf,
+}
+
+impl Drop for Pools {
+    fn drop(&mut self) {
+        for pool in &[SOURCE_POOL, DESTINATION_POOL] {
+            Command::new("zpool")
+                .args(["destroy", "-f", pool])
+                .status()
+                .ok();
+        }
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn zpool(args: &[&str]) {
+    let status = Command::new("zpool").args(args).status().unwrap();
+    if !status.success() {
+        panic!("selftest failed: zpool {}", args.join(" "));
+    }
+}
+
+/// Runs this zbak binary, so the test covers argument parsing as well.
+fn zbak(state: &Path, args: &[&str]) {
+    println!("Running zbak {}.", args.join(" "));
+    let mut cmd = Command::new(std::env::current_exe().unwrap());
+    if crate::TRACE.load(Ordering::Relaxed) {
+        cmd.arg("--trace");
+    }
+    let status = cmd
+        .args(args)
+        .env("ZBAK_STATE_DIR", state)
+        .status()
+        .unwrap();
+    if !status.success() {
+        panic!("selftest failed: zbak {}", args.join(" "));
+    }
+}
+
+fn check(ok: bool, what: &str) {
+    if !ok {
+        panic!("selftest failed: {}", what);
+    }
+    println!("Ok: {}.", what);
+}
+
+fn restore(state: &Path, dir: &Path, snapshot: &str, expected: &str) {
+    let into = dir.join(format!("restore-{}", snapshot));
+    fs::create_dir(&into).unwrap();
+    let spec = format!("{}/data@{}:hello.txt", DESTINATION_POOL, snapshot);
+    zbak(state, &["restore-file", &spec, into.to_str().unwrap()]);
+    let contents = fs::read_to_string(into.join("hello.txt")).unwrap();
+    check(
+        contents == expected,
+        &format!("hello.txt restored from @{} is intact", snapshot),
+    );
+}
+
+fn guids(remote: &Remote) -> Vec<String> {
+    remote
+        .internal_list("snapshot")
+        .into_iter()
+        .map(|x| x.guid)
+        .collect()
+}
+
+/// Creates two small file-backed pools, runs snap, send (full and
+/// incremental), restore-file and pruning against them through the zbak
+/// binary, checks the results and destroys the pools again.
+pub fn selftest() {
+    if unsafe { libc::geteuid() } != 0 {
+        panic!("selftest creates pools and must run as root");
+    }
+    for pool in &[SOURCE_POOL, DESTINATION_POOL] {
+        let exists = Command::new("zpool")
+            .args(["list", pool])
+            .output()
+            .unwrap_or_else(|e| panic!("cannot run zpool: {}", e))
+            .status
+            .success();
+        if exists {
+            panic!("pool {} already exists, refusing to touch it", pool);
+        }
+    }
+
+    let dir = std::env::temp_dir().join(format!("zbak-selftest-{}", std::process::id()));
+    fs::create_dir(&dir).unwrap();
+    let pools = Pools { dir: dir.clone() };
+    let state = dir.join("state");
+
+    for pool in &[SOURCE_POOL, DESTINATION_POOL] {
+        let image = dir.join(format!("{}.img", pool));
+        // 64 MiB is the smallest vdev zpool accepts
+        fs::File::create(&image)
+            .unwrap()
+            .set_len(128 << 20)
+            .unwrap();
+        let mountpoint = dir.join(pool);
+        println!("Creating pool {}.", pool);
+        zpool(&[
+            "create",
+            "-m",
+            mountpoint.to_str().unwrap(),
+            pool,
+            image.to_str().unwrap(),
+        ]);
+    }
+
+    let hello = dir.join(SOURCE_POOL).join("hello.txt");
+    let destination = format!("{}/data", DESTINATION_POOL);
+
+    fs::write(&hello, "first\n").unwrap();
+    zbak(&state, &["snap", SOURCE_POOL, "--keep", "12m"]);
+    let origin = parse_remote(SOURCE_POOL);
+    let first = origin.list_snapshots();
+    check(first.len() == 1, "snap created a snapshot");
+    let first = first[0].path.split_once('@').unwrap().1.to_string();
+
+    let send = [
+        "send",
+        "--name",
+        "selftest",
+        "--from",
+        SOURCE_POOL,
+        "--to",
+        &destination,
+        "--keep",
+        "12m",
+        "--create-dest",
+    ];
+    zbak(&state, &send);
+
+    // snap waits 15 minutes between snapshots, so take the second by hand,
+    // named a minute later and created at least a second later
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    fs::write(&hello, "second\n").unwrap();
+    let time = chrono::Utc::now() + chrono::Duration::minutes(1);
+    let second = time.format("%Y-%m-%dT%H%M").to_string();
+    origin.snapshot(&format!("{}@{}", SOURCE_POOL, second));
+    zbak(&state, &send);
+
+    let replica = parse_remote(&destination);
+    check(
+        guids(&replica) == guids(&origin),
+        "replica has the same snapshots as the source",
+    );
+    check(
+        origin.list_bookmarks("selftest").len() == 1,
+        "send pruned the older bookmark",
+    );
+
+    restore(&state, &dir, &first, "first\n");
+    restore(&state, &dir, &second, "second\n");
+
+    // a policy keeping only the newest of a label expires the older one,
+    // whenever the test runs
+    let labeled = |minutes: i64| {
+        let time = chrono::Utc::now() + chrono::Duration::minutes(minutes);
+        format!("{}@{}-expiring", SOURCE_POOL, time.format("%Y-%m-%dT%H%M"))
+    };
+    let (older, newer) = (labeled(2), labeled(3));
+    origin.snapshot(&older);
+    origin.snapshot(&newer);
+    zbak(&state, &["snap", SOURCE_POOL, "--keep", "12m,expiring=1l"]);
+    let left = origin
+        .list_snapshots()
+        .into_iter()
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
+    check(
+        !left.contains(&older) && left.contains(&newer),
+        "snap --keep destroyed the expired snapshot",
+    );
+
+    drop(pools);
+    println!("Selftest passed.");
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(signal: libc::c_int) {
+    if signal == libc::SIGUSR1 {
+        STATUS_REQUESTED.store(true, Ordering::SeqCst);
+    } else {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Catches SIGINT and SIGTERM so that a run can stop its pipeline and exit
+/// cleanly instead of dying mid-transfer. Installed without SA_RESTART, so
+/// a blocked read returns early and the flag is noticed promptly. SIGUSR1
+/// requests a status report and does restart interrupted calls.
+pub fn install() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut());
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Whether SIGUSR1 arrived since the last call.
+pub fn take_status_request() -> bool {
+    STATUS_REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,224 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Layout of the state directory, bumped whenever a file in it changes
+/// incompatibly. Version 1 holds the instance lock and the progress file.
+/// Version 2 adds the throughput history and a lock per job, and has a
+/// progress file per running job instead.
+const STATE_VERSION: u32 = 2;
+
+/// Directory holding zbak's persistent state: `/var/lib/zbak` for root,
+/// `$XDG_STATE_HOME/zbak` otherwise. `ZBAK_STATE_DIR` overrides both.
+pub fn state_dir() -> PathBuf {
+    let dir = if let Some(dir) = std::env::var_os("ZBAK_STATE_DIR") {
+        PathBuf::from(dir)
+    } else if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from("/var/lib/zbak")
+    } else if let Some(dir) = std::env::var_os("XDG_STATE_HOME") {
+        PathBuf::from(dir).join("zbak")
+    } else {
+        let home = std::env::var_os("HOME").expect("neither XDG_STATE_HOME nor HOME is set");
+        PathBuf::from(home).join(".local/state/zbak")
+    };
+    std::fs::create_dir_all(&dir)
+        .unwrap_or_else(|e| panic!("cannot create state directory {}: {}", dir.display(), e));
+    dir
+}
+
+/// What a run locks the state directory for. Runs of different keys share
+/// it, e.g. `snap` keeps taking snapshots while `send rpi4` is running, but
+/// two runs of one key don't. Exclusive runs, e.g. gc, have it to
+/// themselves.
+pub enum LockScope<'a> {
+    Shared(&'a str),
+    Exclusive,
+}
+
+/// Held for the lifetime of a run; the locks are released when dropped.
+pub struct InstanceLock {
+    _files: Vec<File>,
+}
+
+fn flock(file: &File, flags: libc::c_int) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), flags) == 0 }
+}
+
+fn open_lock(path: &Path) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap_or_else(|e| panic!("cannot open lock file {}: {}", path.display(), e))
+}
+
+/// Records this process in a lock file just taken, for the message of
+/// whoever finds it held.
+fn write_pid(mut file: &File) {
+    file.set_len(0).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    write!(file, "{}", std::process::id()).unwrap();
+}
+
+/// Why `file` could not be locked, naming the pid recorded in it.
+fn held_by(mut file: &File, what: &str) -> String {
+    let mut holder = String::new();
+    file.read_to_string(&mut holder).ok();
+    match holder.trim() {
+        "" => format!("Another zbak {} is running", what),
+        pid => format!("Another zbak {} is running (pid {})", what, pid),
+    }
+}
+
+/// The lock file of `key`, which is written like a job name may be.
+fn key_path(dir: &Path, key: &str) -> PathBuf {
+    let mut name = String::from("lock.");
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' => name.push(byte as char),
+            _ => name += &format!("%{:02x}", byte),
+        }
+    }
+    dir.join(name)
+}
+
+/// Takes the locks of `scope` in `dir`: the directory's lock, shared or
+/// exclusive, and the lock of a shared run's key. With `wait`, blocks until
+/// they are free instead of returning why they aren't.
+fn lock_dir(dir: &Path, scope: &LockScope, wait: bool) -> Result<InstanceLock, String> {
+    let nonblocking = if wait { 0 } else { libc::LOCK_NB };
+    let instance = open_lock(&dir.join("lock"));
+    let mut files = vec![];
+    match scope {
+        LockScope::Exclusive => {
+            if !flock(&instance, libc::LOCK_EX | nonblocking) {
+                // the pid in there is an exclusive run's, maybe a finished one
+                if flock(&instance, libc::LOCK_SH | libc::LOCK_NB) {
+                    return Err("Another zbak snap or send is running".to_string());
+                }
+                return Err(held_by(&instance, "instance"));
+            }
+            write_pid(&instance);
+            files.push(instance);
+        }
+        LockScope::Shared(key) => {
+            // only an exclusive run leaves its pid in there
+            if !flock(&instance, libc::LOCK_SH | nonblocking) {
+                return Err(held_by(&instance, "instance"));
+            }
+            files.push(instance);
+            let own = open_lock(&key_path(dir, key));
+            if !flock(&own, libc::LOCK_EX | nonblocking) {
+                return Err(held_by(&own, key));
+            }
+            write_pid(&own);
+            files.push(own);
+        }
+    }
+    Ok(InstanceLock { _files: files })
+}
+
+/// Makes sure no other zbak runs what `scope` locks. If another instance
+/// holds the lock, either wait for it to finish or exit with an
+/// explanation.
+pub fn lock_instance(scope: LockScope, wait: bool) -> InstanceLock {
+    let dir = state_dir();
+    let lock = match lock_dir(&dir, &scope, false) {
+        Ok(lock) => lock,
+        Err(held) if !wait => {
+            eprintln!("{}. Pass --wait to queue behind it.", held);
+            std::process::exit(1);
+        }
+        Err(held) => {
+            println!("{}, waiting.", held);
+            lock_dir(&dir, &scope, true).unwrap_or_else(|e| panic!("cannot lock: {}", e))
+        }
+    };
+    migrate(&dir);
+    lock
+}
+
+/// Brings the state directory up to the current layout, refusing to touch
+/// state written by a newer zbak. Runs under the instance lock.
+fn migrate(dir: &Path) {
+    let path = dir.join("version");
+    let version = match std::fs::read_to_string(&path) {
+        Ok(x) => x
+            .trim()
+            .parse::<u32>()
+            .unwrap_or_else(|_| panic!("invalid state version in {}", path.display())),
+        // state from before versioning has the same layout as version 1
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => panic!("cannot read {}: {}", path.display(), e),
+    };
+
+    if version > STATE_VERSION {
+        eprintln!(
+            "{} was written by a newer zbak (state version {}, this zbak knows up to {}). Upgrade zbak or set ZBAK_STATE_DIR.",
+            dir.display(),
+            version,
+            STATE_VERSION
+        );
+        std::process::exit(1);
+    }
+    if version < STATE_VERSION {
+        // upgrades from older layouts, one version at a time
+        if version < 2 {
+            // left by a version 1 run that was killed; one still running
+            // would hold the lock we are under
+            for name in ["progress", "progress.tmp"] {
+                std::fs::remove_file(dir.join(name)).ok();
+            }
+        }
+        std::fs::write(&path, format!("{}\n", STATE_VERSION))
+            .unwrap_or_else(|e| panic!("cannot write {}: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snap_runs_during_a_send() {
+        let dir = std::env::temp_dir().join(format!("zbak-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let send = lock_dir(&dir, &LockScope::Shared("send rpi4"), false).unwrap();
+        assert!(lock_dir(&dir, &LockScope::Shared("snap"), false).is_ok());
+        let again = lock_dir(&dir, &LockScope::Shared("send rpi4"), false);
+        let held = format!(
+            "Another zbak send rpi4 is running (pid {})",
+            std::process::id()
+        );
+        assert_eq!(again.err(), Some(held));
+        let exclusive = lock_dir(&dir, &LockScope::Exclusive, false);
+        let held = "Another zbak snap or send is running".to_string();
+        assert_eq!(exclusive.err(), Some(held));
+        drop(send);
+        assert!(lock_dir(&dir, &LockScope::Exclusive, false).is_ok());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let dir = std::env::temp_dir().join(format!("zbak-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("version"), "1\n").unwrap();
+        std::fs::write(dir.join("progress"), "").unwrap();
+
+        migrate(&dir);
+        assert!(!dir.join("progress").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("version")).unwrap(),
+            format!("{}\n", STATE_VERSION)
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
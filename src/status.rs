@@ -0,0 +1,151 @@
+use crate::{panic_message, parse_remote, Snapshot};
+use chrono::{DateTime, Utc};
+
+fn format_age(age: chrono::Duration) -> String {
+    let minutes = age.num_minutes().max(0);
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+/// How fresh one location's snapshots are, as far as it could be listed.
+enum Freshness {
+    Failed(String),
+    Empty,
+    Newest {
+        name: String,
+        age: chrono::Duration,
+        count: usize,
+        stale: bool,
+    },
+}
+
+impl Freshness {
+    /// A check_mk state: 0 for OK, 2 for CRIT and 3 for UNKNOWN. zabbix
+    /// gets the same numbers.
+    fn state(&self) -> u8 {
+        match self {
+            Freshness::Newest { stale: false, .. } => 0,
+            Freshness::Newest { stale: true, .. } | Freshness::Empty => 2,
+            Freshness::Failed(_) => 3,
+        }
+    }
+}
+
+/// Lists all `locations` at once and prints how fresh each one's newest
+/// snapshot is, as a table or for `format` (zabbix or checkmk). Returns how
+/// many are stale (older than `max_age`) or could not be listed.
+pub fn status(
+    locations: &[String],
+    max_age: Option<std::time::Duration>,
+    format: &str,
+    now: &DateTime<Utc>,
+) -> usize {
+    let results: Vec<Result<Vec<Snapshot>, String>> = std::thread::scope(|scope| {
+        let threads = locations
+            .iter()
+            .map(|location| scope.spawn(move || parse_remote(location).list_snapshots()))
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .map(|x| x.join().map_err(panic_message))
+            .collect()
+    });
+    let rows = results
+        .into_iter()
+        .map(|result| match result {
+            Err(e) => Freshness::Failed(e),
+            Ok(snapshots) => match snapshots.last() {
+                None => Freshness::Empty,
+                Some(newest) => {
+                    let age = *now - newest.time;
+                    Freshness::Newest {
+                        name: newest.path.split_once('@').unwrap().1.to_string(),
+                        age,
+                        count: snapshots.len(),
+                        stale: max_age.is_some_and(|x| age.to_std().is_ok_and(|age| age > x)),
+                    }
+                }
+            },
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        "text" => print_table(locations, &rows),
+        "zabbix" => print_zabbix(locations, &rows),
+        "checkmk" => print_checkmk(locations, &rows),
+        other => panic!("unknown format {}, expected text, zabbix or checkmk", other),
+    }
+    rows.iter().filter(|x| x.state() != 0).count()
+}
+
+fn print_table(locations: &[String], rows: &[Freshness]) {
+    let width = locations.iter().map(|x| x.len()).max().unwrap();
+    for (location, row) in locations.iter().zip(rows) {
+        match row {
+            Freshness::Failed(e) => println!("{:width$}  error: {}", location, e, width = width),
+            Freshness::Empty => println!("{:width$}  no snapshots", location, width = width),
+            Freshness::Newest {
+                name,
+                age,
+                count,
+                stale,
+            } => println!(
+                "{:width$}  {}  {:>7} old  {} snapshot(s){}",
+                location,
+                name,
+                format_age(*age),
+                count,
+                if *stale { "  STALE" } else { "" },
+                width = width
+            ),
+        }
+    }
+}
+
+/// Input for `zabbix_sender -i -`, with `-` standing for the host in the
+/// agent's configuration.
+fn print_zabbix(locations: &[String], rows: &[Freshness]) {
+    for (location, row) in locations.iter().zip(rows) {
+        let key = |name: &str| format!("zbak.{}[\"{}\"]", name, location);
+        println!("- {} {}", key("state"), row.state());
+        if let Freshness::Newest { age, count, .. } = row {
+            println!("- {} {}", key("age"), age.num_seconds().max(0));
+            println!("- {} {}", key("snapshots"), count);
+        }
+    }
+}
+
+/// check_mk local check lines, one service per location.
+fn print_checkmk(locations: &[String], rows: &[Freshness]) {
+    for (location, row) in locations.iter().zip(rows) {
+        let (metrics, summary) = match row {
+            Freshness::Failed(e) => ("-".to_string(), format!("error: {}", e)),
+            Freshness::Empty => ("-".to_string(), "no snapshots".to_string()),
+            Freshness::Newest {
+                name,
+                age,
+                count,
+                stale,
+            } => (
+                format!("age={}|snapshots={}", age.num_seconds().max(0), count),
+                format!(
+                    "{}newest {} is {} old",
+                    if *stale { "stale, " } else { "" },
+                    name,
+                    format_age(*age)
+                ),
+            ),
+        };
+        // a newline in an error would start a bogus line of its own
+        println!(
+            "{} \"zbak {}\" {} {}",
+            row.state(),
+            location,
+            metrics,
+            summary.replace('\n', " ")
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crate::progress::json_string;
+use chrono::{DateTime, Utc};
+use std::{
+    cell::RefCell,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+/// What became of one dataset snap was given, or of a send.
+struct Job {
+    name: String,
+    status: &'static str,
+    started: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+    bytes: u64,
+    error: Option<String>,
+}
+
+/// Where `--summary-file` asked for the summary to go, and when the run
+/// started.
+static TARGET: OnceLock<(String, DateTime<Utc>)> = OnceLock::new();
+static JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// The job started last on this thread, as parallel sends each have
+    /// their own.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` on this thread's current job, if there is one.
+fn with_current<T>(f: impl FnOnce(&mut Job) -> T) -> Option<T> {
+    let mut jobs = JOBS.lock().unwrap();
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        jobs.iter_mut()
+            .rev()
+            .find(|x| Some(&x.name) == current.as_ref())
+            .map(f)
+    })
+}
+
+/// Makes `write` save a summary of this run to `path`.
+pub fn enable(path: &str) {
+    TARGET.set((path.to_string(), Utc::now())).ok();
+}
+
+/// Starts a job, replacing an earlier attempt at it. The calls below apply
+/// to the job started last on the same thread.
+pub fn start(name: &str) {
+    let mut jobs = JOBS.lock().unwrap();
+    jobs.retain(|x| x.name != name);
+    jobs.push(Job {
+        name: name.to_string(),
+        status: "running",
+        started: Utc::now(),
+        finished: None,
+        bytes: 0,
+        error: None,
+    });
+    CURRENT.with(|x| *x.borrow_mut() = Some(name.to_string()));
+}
+
+pub fn add_bytes(bytes: u64) {
+    with_current(|job| job.bytes += bytes);
+}
+
+/// Ends the current job as `status`, e.g. ok or skipped, unless it already
+/// ended.
+pub fn finish(status: &'static str) {
+    with_current(|job| {
+        if job.finished.is_none() {
+            job.status = status;
+            job.finished = Some(Utc::now());
+        }
+    });
+}
+
+/// Ends the current job as failed with `error`.
+pub fn fail(error: &str) {
+    with_current(|job| {
+        job.status = "failed";
+        job.error = Some(error.to_string());
+        job.finished = Some(Utc::now());
+    });
+}
+
+/// Whether the current job failed.
+pub fn failed() -> bool {
+    with_current(|job| job.status == "failed").unwrap_or(false)
+}
+
+fn json_time(time: Option<DateTime<Utc>>) -> String {
+    time.map_or("null".to_string(), |x| {
+        json_string(&x.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+    })
+}
+
+/// Writes the summary, if one was asked for, ending jobs still running as
+/// failed with `error`, e.g. a panic's message. The file is replaced
+/// atomically, so a health check never reads half of it.
+pub fn write(exit_status: i32, error: Option<&str>) {
+    let (path, started) = match TARGET.get() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut jobs = JOBS.lock().unwrap();
+    for job in jobs.iter_mut().filter(|x| x.finished.is_none()) {
+        job.status = "failed";
+        job.error = Some(error.unwrap_or("interrupted").to_string());
+        job.finished = Some(Utc::now());
+    }
+
+    let jobs = jobs
+        .iter()
+        .map(|job| {
+            format!(
+                "{{\"job\":{},\"status\":{},\"started\":{},\"finished\":{},\"bytes\":{},\"error\":{}}}",
+                json_string(&job.name),
+                json_string(job.status),
+                json_time(Some(job.started)),
+                json_time(job.finished),
+                job.bytes,
+                job.error.as_deref().map_or("null".to_string(), json_string)
+            )
+        })
+        .collect::<Vec<_>>();
+    let contents = format!(
+        "{{\"started\":{},\"finished\":{},\"exit_status\":{},\"jobs\":[{}]}}\n",
+        json_time(Some(*started)),
+        json_time(Some(Utc::now())),
+        exit_status,
+        jobs.join(",")
+    );
+
+    let path = Path::new(path);
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).ok();
+    }
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, path)) {
+        eprintln!("Warning: cannot write {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{parse_window, Window};
+use std::{
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    time::{Duration, Instant},
+};
+
+struct Rule {
+    /// Bytes per second, or `None` for unlimited
+    rate: Option<u64>,
+    window: Option<Window>,
+}
+
+/// A bandwidth limit that may vary with the local time of day.
+pub struct Schedule {
+    rules: Vec<Rule>,
+}
+
+impl Schedule {
+    /// The first rule whose window contains `time` wins. Without a
+    /// matching rule the transfer is unlimited.
+    fn rate_at(&self, time: chrono::NaiveTime) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|rule| rule.window.as_ref().is_none_or(|w| w.contains(time)))
+            .and_then(|rule| rule.rate)
+    }
+}
+
+/// Parses a number of bytes with an optional K, M or G suffix, e.g. 512M.
+pub fn parse_bytes(input: &str) -> u64 {
+    let (digits, multiplier) = match input.chars().last() {
+        Some('K') => (&input[..input.len() - 1], 1 << 10),
+        Some('M') => (&input[..input.len() - 1], 1 << 20),
+        Some('G') => (&input[..input.len() - 1], 1 << 30),
+        _ => (input, 1),
+    };
+    digits
+        .parse::<u64>()
+        .unwrap_or_else(|_| panic!("invalid size {}", input))
+        * multiplier
+}
+
+fn parse_rate(input: &str) -> Option<u64> {
+    if input == "unlimited" {
+        return None;
+    }
+    let rate = parse_bytes(input);
+    if rate == 0 {
+        panic!("Cowardly refusing to limit bandwidth to zero.");
+    }
+    Some(rate)
+}
+
+/// Parses e.g. `5M@08:00-18:00,20M`: 5 MiB/s during office hours, 20 MiB/s
+/// otherwise.
+pub fn parse_schedule(input: &str) -> Schedule {
+    let rules = input
+        .split(',')
+        .map(|part| match part.split_once('@') {
+            Some((rate, window)) => Rule {
+                rate: parse_rate(rate),
+                window: Some(parse_window(window)),
+            },
+            None => Rule {
+                rate: parse_rate(part),
+                window: None,
+            },
+        })
+        .collect();
+    Schedule { rules }
+}
+
+/// Copies `reader` into `writer`, re-checking the schedule after every chunk
+/// so that long transfers pick up changes in the limit. `on_chunk` is told
+/// the size of every chunk written.
+///
+/// Fails with `Interrupted` once a termination signal arrives and with
+/// `TimedOut` once `deadline` passes, even while the reader is stalled.
+pub fn copy(
+    reader: &mut (impl Read + AsRawFd),
+    writer: &mut impl Write,
+    schedule: Option<&Schedule>,
+    deadline: Option<Instant>,
+    mut on_chunk: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+
+    let mut slot_start = Instant::now();
+    let mut slot_bytes = 0;
+    let mut slot_rate = None;
+
+    loop {
+        check(deadline)?;
+        if !wait_readable(reader, Duration::from_secs(1)) {
+            continue;
+        }
+
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+        on_chunk(n as u64);
+
+        let rate = schedule.and_then(|x| x.rate_at(chrono::Local::now().time()));
+        if rate != slot_rate || slot_start.elapsed() >= Duration::from_secs(1) {
+            slot_start = Instant::now();
+            slot_bytes = 0;
+            slot_rate = rate;
+        }
+        slot_bytes += n as u64;
+
+        if let Some(rate) = rate {
+            let target = Duration::from_secs_f64(slot_bytes as f64 / rate as f64);
+            let elapsed = slot_start.elapsed();
+            if target > elapsed {
+                pause(target - elapsed, deadline)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Fails the way `copy` does once a termination signal arrived or
+/// `deadline` passed.
+fn check(deadline: Option<Instant>) -> io::Result<()> {
+    if crate::signal::interrupted() {
+        return Err(io::ErrorKind::Interrupted.into());
+    }
+    if deadline.is_some_and(|x| Instant::now() > x) {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    Ok(())
+}
+
+/// Sleeps for `duration` in short slices, so that a slow limit doesn't hold
+/// off a termination signal or `deadline`.
+fn pause(duration: Duration, deadline: Option<Instant>) -> io::Result<()> {
+    let end = Instant::now() + duration;
+    loop {
+        check(deadline)?;
+        let left = end.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(());
+        }
+        std::thread::sleep(left.min(Duration::from_millis(100)));
+    }
+}
+
+/// Waits up to `timeout` for `reader` to have data (or EOF) available.
+fn wait_readable(reader: &impl AsRawFd, timeout: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: reader.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
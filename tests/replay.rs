@@ -0,0 +1,70 @@
+//! Runs zbak against fixtures recorded with `ZBAK_RECORD`, see src/replay.rs.
+#![cfg(feature = "replay")]
+
+use std::{path::PathBuf, process::Command};
+
+/// Runs zbak with `args`, replaying `fixture` from tests/fixtures, and
+/// returns its exit status and what it printed.
+fn replay(fixture: &str, args: &[&str]) -> (i32, String) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let state =
+        std::env::temp_dir().join(format!("zbak-replay-{}-{}", std::process::id(), fixture));
+    std::fs::create_dir_all(&state).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_zbak"))
+        .args(args)
+        .env("ZBAK_REPLAY", dir.join(fixture))
+        .env("ZBAK_STATE_DIR", &state)
+        .env("TZ", "UTC")
+        .env_remove("ZBAK_RECORD")
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&state).ok();
+    let printed =
+        String::from_utf8_lossy(&out.stdout).into_owned() + &String::from_utf8_lossy(&out.stderr);
+    (out.status.code().unwrap(), printed)
+}
+
+/// The incremental send the send fixtures were recorded from.
+const SEND: [&str; 9] = [
+    "send",
+    "--from",
+    "zroot/code",
+    "--to",
+    "rpool/code",
+    "--name",
+    "job",
+    "--keep",
+    "24h7d",
+];
+
+#[test]
+fn snap() {
+    let (status, printed) = replay("snap.fixture", &["snap", "zroot/code", "--keep", "24h7d"]);
+    assert_eq!(status, 0, "{}", printed);
+    assert!(printed.contains("Creating snapshot zroot/code@2026-10-14T1838."));
+}
+
+#[test]
+fn incremental_send() {
+    let (status, printed) = replay("send.fixture", &SEND);
+    assert_eq!(status, 0, "{}", printed);
+    assert!(printed
+        .contains("Sending zroot/code#2026-10-14T1200-sync-job -> zroot/code@2026-10-14T1838."));
+    assert!(printed.contains("Done."));
+}
+
+#[test]
+fn refuses_written_replica() {
+    let (status, printed) = replay("send-written.fixture", &SEND);
+    assert_eq!(status, 1, "{}", printed);
+    assert!(
+        printed.contains("rpool/code was written to since rpool/code@2026-10-14T1200 (4.0 KiB)")
+    );
+}
+
+#[test]
+fn refuses_recreated_source() {
+    let (status, printed) = replay("send-recreated.fixture", &SEND);
+    assert_eq!(status, 1, "{}", printed);
+    assert!(printed.contains("zroot/code has guid 777; it was recreated or replaced"));
+}